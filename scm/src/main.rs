@@ -1,393 +1,5589 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey, SECRET_KEY_LENGTH};
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde_json::json;
 use sha2::{Digest, Sha512};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
+use std::fmt;
 use std::fs;
+use std::io::{self, BufRead, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
 use std::path::{Path, PathBuf};
-use std::process;
+use std::process::{self, Command};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-const SCM_FILE: &str = ".scm";
+const SCM_DIR: &str = ".scm";
+/// Where the single-file repository is kept after migrating it to `.scm/`
+const LEGACY_BACKUP: &str = ".scm.old";
+/// Directory the new `.scm/` is built in while migrating, which also keeps other commands out
+const MIGRATING_DIR: &str = ".scm.migrating";
+/// Copy of the repository's mutable files from before the last command that changed them
+const BACKUP_DIR: &str = ".scm.bak";
+/// Files under `.scm/` that are rewritten in place rather than added as objects; the commit
+/// list only ever grows, so its backup is its length in `commits.length`
+const MUTABLE_FILES: [&str; 3] = ["state", "config", "stash"];
+/// File under `.scm/` listing the public keys `scm verify` accepts, one per line
+const TRUSTED_KEYS: &str = "trusted-keys";
+/// Last line of a file that does not end in a newline; NUL never occurs in text files
+const NO_NEWLINE: &str = "\0\\ No newline at end of file";
+/// Start of the single line holding a binary file as hex
+const BINARY_PREFIX: &str = "\0binary ";
+/// Start of the single line of a diff that replaces a binary file whole, followed by the old and
+/// new blob ids
+const BLOB_CHANGE: &str = "\0replace ";
+/// Per-directory file listing paths to leave out of commits
+const IGNORE_FILE: &str = ".scmignore";
+/// Branch created along with the first commit
+const DEFAULT_BRANCH: &str = "main";
+/// Minimum share of common lines for a deleted and an added file to count as a rename
+const RENAME_THRESHOLD: f64 = 0.5;
+/// Shortest hash prefix `scm diff` takes for a commit rather than a path
+const MIN_HASH_PREFIX: usize = 4;
+/// Commits between full snapshots when `scm gc` stores objects as chains of deltas
+const KEYFRAME_INTERVAL: usize = 16;
+/// Largest request body `scm serve` accepts; pushes carry every new object, hex-encoded
+const MAX_REQUEST_BODY: u64 = 256 << 20;
+/// Longest request or header line `scm serve` accepts
+const MAX_HEADER_LINE: u64 = 8 << 10;
+/// How long either end of an `scm serve` connection waits on a silent peer before giving up
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(30);
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 struct CommitEntry {
     hash: String,
-    init: HashMap<String, Vec<String>>,
+    /// Hashes of the commits this one was made on top of; empty for the first commit and for
+    /// commits made before branches existed, whose parent is the previous commit
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    parents: Vec<String>,
+    /// Tree object holding a snapshot of every file
+    tree: String,
+    /// Stash entries only: tree object of the files that were staged, if any were
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    staged: String,
+    /// Blob ids of files added in this commit
+    init: HashMap<String, String>,
     diff: HashMap<String, Vec<String>>,
+    /// Files removed in this commit
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deleted: Vec<String>,
+    /// Files moved in this commit, from old path to new path
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    renamed: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    message: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    author: String,
+    /// Seconds since the Unix epoch when the commit was made
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    /// Made after the hash is computed, so signing never changes it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<CommitSignature>,
+}
+
+/// Ed25519 signature of a commit's hash, by a key from `scm keygen`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct CommitSignature {
+    /// Public key of the signer, in hex
+    key: String,
+    /// Signature of the hex commit hash, in hex
+    sig: String,
 }
 
-# [derive(Serialize, Deserialize, Debug)]
+/// Repository contents loaded from `.scm/`
+#[derive(Debug)]
 struct ScmData {
     latest: HashMap<String, Vec<String>>,
     commits: Vec<CommitEntry>,
     merkle: Vec<Vec<String>>,
+    /// Index of the checked-out commit when it is not the tip of `branch`
+    head: Option<usize>,
+    /// Branch HEAD refers to, which new commits are added to
+    branch: String,
+    /// Tip commit hash of every branch
+    branches: BTreeMap<String, String>,
+    /// Hash of the commit being merged in while conflicts are resolved
+    merging: Option<String>,
+    /// Files the merge in progress added to the working tree, which aborting it removes
+    merge_added: Vec<String>,
+    /// Files the merge in progress could not merge, until each is staged with `scm add`
+    conflicts: Vec<String>,
+    /// Object ids of `commits`, in the same order
+    ids: Vec<String>,
+    /// Blob ids of the files staged for the next commit, when they differ from `latest`
+    index: Option<BTreeMap<String, String>>,
+    tags: BTreeMap<String, Tag>,
+    /// Number of commits `.scm/commits` already has a summary line for; `None` when it must be
+    /// written whole
+    listed: Option<usize>,
+    /// Whether `commits` holds full commit objects rather than the hash, tree and parents kept
+    /// in `.scm/commits`; see `load_history`
+    history_loaded: bool,
+}
+
+/// Fixed name for a commit, such as a release; annotated tags also say who made them, when
+/// and why
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Tag {
+    commit: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    message: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    tagger: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+}
+
+/// Mutable repository state kept in `.scm/state`
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RepoState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    head: Option<usize>,
+    #[serde(default)]
+    branch: String,
+    #[serde(default)]
+    branches: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    merging: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    merge_added: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    conflicts: Vec<String>,
+    #[serde(default)]
+    merkle_root: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index: Option<BTreeMap<String, String>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tags: BTreeMap<String, Tag>,
+}
+
+/// Commit as stored in the single-file `.scm` format
+#[derive(Deserialize, Debug)]
+struct LegacyCommitEntry {
+    hash: String,
+    init: HashMap<String, Vec<String>>,
+    diff: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    deleted: Vec<String>,
+    #[serde(default)]
+    renamed: HashMap<String, String>,
+}
+
+/// Whole repository as stored in the single-file `.scm` format
+#[derive(Deserialize, Debug)]
+struct LegacyScmData {
+    commits: Vec<LegacyCommitEntry>,
+    #[serde(default)]
+    head: Option<usize>,
+}
+
+/// Everything that can make a command fail, each with its own exit code
+#[derive(Debug)]
+enum ScmError {
+    /// The command line was not understood
+    Usage(String),
+    /// Reading or writing a file failed
+    Io(String),
+    /// A repository file is not in the expected format
+    Parse(String),
+    /// No commit matches a reference
+    MissingCommit(String),
+    /// Uncommitted changes would be lost
+    DirtyTree(String),
+    /// Stored history is missing objects, fails to replay or does not match its hashes
+    CorruptHistory(String),
+    /// The repository is not in a state that allows the command
+    Refused(String),
+    /// Another command holds the repository lock
+    Locked(String),
+}
+
+impl ScmError {
+    /// The error a server reported with `exit_code`, for the client to fail the same way
+    fn from_exit_code(code: i32, msg: String) -> ScmError {
+        match code {
+            2 => ScmError::Usage(msg),
+            3 => ScmError::Io(msg),
+            4 => ScmError::Parse(msg),
+            5 => ScmError::MissingCommit(msg),
+            6 => ScmError::DirtyTree(msg),
+            7 => ScmError::CorruptHistory(msg),
+            8 => ScmError::Locked(msg),
+            _ => ScmError::Refused(msg),
+        }
+    }
+    
+    fn exit_code(&self) -> i32 {
+        match self {
+            ScmError::Refused(_) => 1,
+            ScmError::Usage(_) => 2,
+            ScmError::Io(_) => 3,
+            ScmError::Parse(_) => 4,
+            ScmError::MissingCommit(_) => 5,
+            ScmError::DirtyTree(_) => 6,
+            ScmError::CorruptHistory(_) => 7,
+            ScmError::Locked(_) => 8,
+        }
+    }
+}
+
+impl fmt::Display for ScmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScmError::Io(msg) => write!(f, "I/O error: {}", msg),
+            ScmError::Parse(msg) => write!(f, "Cannot parse {}", msg),
+            ScmError::Usage(msg)
+            | ScmError::MissingCommit(msg)
+            | ScmError::DirtyTree(msg)
+            | ScmError::CorruptHistory(msg)
+            | ScmError::Refused(msg)
+            | ScmError::Locked(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// Turn an I/O error into an `ScmError` naming what was being done
+fn io_error(context: impl fmt::Display) -> impl FnOnce(io::Error) -> ScmError {
+    move |e| ScmError::Io(format!("{}: {}", context, e))
+}
+
+/// Fail unless the current directory holds a repository
+fn require_repo() -> Result<(), ScmError> {
+    if Path::new(SCM_DIR).exists() {
+        Ok(())
+    } else {
+        Err(ScmError::Refused("No .scm file found. Initialize with 'scm commit' first.".to_string()))
+    }
+}
+
+/// Held by commands that change the repository; dropping it releases the lock
+struct RepoLock {
+    /// Absolute, so the right lock is released even after changing directory
+    path: PathBuf,
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Take the repository lock, then save the current mutable files to `.scm.bak`
+fn lock_repo() -> Result<RepoLock, ScmError> {
+    if Path::new(SCM_DIR).is_file() {
+        migrate_legacy()?;
+    }
+    let path = scm_path("lock");
+    let mut file = match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            // The holder may not have written its pid yet
+            let holder = fs::read_to_string(&path).unwrap_or_default();
+            let holder = match holder.trim() {
+                "" => String::new(),
+                pid => format!(" (pid {})", pid),
+            };
+            return Err(ScmError::Locked(format!(
+                "Repository locked by another scm command{}; if none is running, remove {}",
+                holder,
+                path.display()
+            )));
+        }
+        Err(e) => return Err(ScmError::Io(format!("{}: {}", path.display(), e))),
+    };
+    let lock = RepoLock {
+        path: env::current_dir().map(|dir| dir.join(&path)).unwrap_or_else(|_| path.clone()),
+    };
+    writeln!(file, "{}", process::id()).map_err(io_error(path.display()))?;
+    fs::create_dir_all(BACKUP_DIR).map_err(io_error(BACKUP_DIR))?;
+    for name in MUTABLE_FILES {
+        match fs::read(scm_path(name)) {
+            Ok(bytes) => write_atomic(&Path::new(BACKUP_DIR).join(name), &bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(ScmError::Io(format!("{}: {}", scm_path(name).display(), e))),
+        }
+    }
+    let length = fs::metadata(scm_path("commits")).map(|meta| meta.len()).unwrap_or(0);
+    write_atomic(&Path::new(BACKUP_DIR).join("commits.length"), format!("{}\n", length).as_bytes())?;
+    Ok(lock)
+}
+
+/// Replace a file so that readers and crashes see either the old or the new content: write a
+/// temporary file beside it, flush it to disk, then rename it over the original
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), ScmError> {
+    let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let temp = path.with_file_name(format!(".{}.{}.tmp", name, process::id()));
+    let written = fs::File::create(&temp).and_then(|mut file| {
+        file.write_all(bytes)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&temp, path)) {
+        fs::remove_file(&temp).ok();
+        return Err(ScmError::Io(format!("{}: {}", path.display(), e)));
+    }
+    // The rename itself is durable once the directory is flushed
+    if let Some(dir) = path.parent().and_then(|dir| fs::File::open(dir).ok()) {
+        dir.sync_all().ok();
+    }
+    Ok(())
+}
+
+/// First 16 hex digits of a hash, for messages
+fn short(hash: &str) -> &str {
+    hash.get(..16).unwrap_or(hash)
+}
+
+/// Version of the `--json` output schema (see JSON.md); raised only when a field is removed or
+/// changes meaning, never for new fields
+const JSON_VERSION: u32 = 1;
+
+/// Everything a command outputs with `--json`, printed as one object when it finishes
+#[derive(Serialize, Debug)]
+struct JsonReport {
+    version: u32,
+    command: String,
+    /// False exactly when `error` is present
+    ok: bool,
+    /// What the command found or did, in a shape documented per command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+    /// Progress lines the command prints without `--json`
+    messages: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorReply>,
+}
+
+thread_local! {
+    /// Output gathered for `--json`; `None` when printing text
+    static REPORT: RefCell<Option<JsonReport>> = const { RefCell::new(None) };
+}
+
+fn json_output() -> bool {
+    REPORT.with(|report| report.borrow().is_some())
+}
+
+/// Print a line about what a command did, or keep it in the report with `--json`
+fn say(line: impl fmt::Display) {
+    let line = REPORT.with(|report| match report.borrow_mut().as_mut() {
+        Some(report) => {
+            report.messages.push(line.to_string());
+            None
+        }
+        None => Some(line),
+    });
+    if let Some(line) = line {
+        print_lines(&[line.to_string()]);
+    }
+}
+
+/// Print a line for people on stderr, where it stays out of output meant for files and pipes,
+/// or keep it in the report's messages with `--json`
+fn say_err(line: impl fmt::Display) {
+    let line = REPORT.with(|report| match report.borrow_mut().as_mut() {
+        Some(report) => {
+            report.messages.push(line.to_string());
+            None
+        }
+        None => Some(line),
+    });
+    if let Some(line) = line {
+        eprintln!("{}", line);
+    }
+}
+
+/// Set the `result` of the report with `--json`
+fn report<T: Serialize + ?Sized>(result: &T) {
+    REPORT.with(|report| {
+        if let Some(report) = report.borrow_mut().as_mut() {
+            report.result = serde_json::to_value(result).ok();
+        }
+    });
+}
+
+/// Print the report with `--json`, once
+fn print_report() {
+    if let Some(report) = REPORT.with(|report| report.borrow_mut().take()) {
+        print_lines(&[String::from_utf8_lossy(&to_json(&report)).to_string()]);
+    }
+}
+
+/// End the command with an exit code, printing the report with `--json`
+fn finish(code: i32) -> ! {
+    print_report();
+    process::exit(code)
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
+    let mut args: Vec<String> = env::args().collect();
+    // The flag applies to every command, so it may come before or after the command name
+    if let Some(pos) = args.iter().position(|arg| arg == "--json") {
+        args.remove(pos);
+        let report = JsonReport {
+            version: JSON_VERSION,
+            command: args.get(1).cloned().unwrap_or_default(),
+            ok: true,
+            result: None,
+            messages: Vec::new(),
+            error: None,
+        };
+        REPORT.with(|cell| *cell.borrow_mut() = Some(report));
+    }
+    if args.len() < 2 && !json_output() {
         print_usage();
-        process::exit(1);
+        process::exit(ScmError::Usage(String::new()).exit_code());
     }
-    match args[1].as_str() {
-        "init" | "commit" => commit(),
+    let result = match args.get(1).map_or("", String::as_str) {
+        "" => Err(ScmError::Usage("No command given".to_string())),
+        "init" | "commit" => commit(&args[2..]),
+        "config" => config(&args[2..]),
         "revert" => revert(),
-        "log" => log(),
-        "status" => status(),
-        _ => {
-            eprintln!("Unknown command: {}", args[1]);
-            print_usage();
-            process::exit(1);
+        "checkout" => checkout(&args[2..]),
+        "branch" => branch(&args[2..]),
+        "switch" => switch(&args[2..]),
+        "merge" => merge(&args[2..]),
+        "tag" => tag(&args[2..]),
+        "stash" => stash(&args[2..]),
+        "add" => add(&args[2..]),
+        "reset" => reset(&args[2..]),
+        "log" => log(&args[2..]),
+        "blame" => blame(&args[2..]),
+        "fsck" => fsck(),
+        "gc" => gc(&args[2..]),
+        "keygen" => keygen(&args[2..]),
+        "verify" => verify(&args[2..]),
+        "prove" => prove(&args[2..]),
+        "verify-proof" => verify_proof(&args[2..]),
+        "diff" => diff(&args[2..]),
+        "status" => status(&args[2..]),
+        "clone" => clone(&args[2..]),
+        "pull" => pull(&args[2..]),
+        "push" => push(&args[2..]),
+        "serve" => serve(&args[2..]),
+        _ => Err(ScmError::Usage(format!("Unknown command: {}", args[1]))),
+    };
+    if let Err(e) = result {
+        let error = ErrorReply { error: e.to_string(), code: e.exit_code() };
+        let reported = REPORT.with(|report| match report.borrow_mut().as_mut() {
+            Some(report) => {
+                report.ok = false;
+                report.error = Some(error);
+                true
+            }
+            None => false,
+        });
+        if !reported {
+            eprintln!("{}", e);
+            if let ScmError::Usage(_) = e {
+                print_usage();
+            }
         }
+        finish(e.exit_code());
     }
+    finish(0)
 }
 
 fn print_usage() {
     eprintln!("Usage: scm <command>");
     eprintln!("Commands:");
-    eprintln!("  init/commit  - Initialize or save current state");
-    eprintln!("  revert       - Roll back to previous commit");
-    eprintln!("  log          - Show commit history");
-    eprintln!("  status       - Show current SCM status");
+    eprintln!("  init/commit [-a] [-m <message>]");
+    eprintln!("               - Initialize with every file, then commit staged changes (all with -a);");
+    eprintln!("                 without -m, $EDITOR is opened if set");
+    eprintln!("  add [-p] <paths>...");
+    eprintln!("               - Stage files for the next commit, or pick hunks with -p");
+    eprintln!("  reset [<paths>...]");
+    eprintln!("               - Unstage files");
+    eprintln!("  config author [<name>]");
+    eprintln!("               - Show or set the author of new commits ($SCM_AUTHOR takes precedence)");
+    eprintln!("  config signing-key [<key-file>]");
+    eprintln!("               - Show or set the key new commits are signed with ($SCM_SIGNING_KEY");
+    eprintln!("                 takes precedence)");
+    eprintln!("  revert       - Move the current branch back to its previous commit");
+    eprintln!("  checkout <hash-prefix|#idx> [--force]");
+    eprintln!("               - Restore the working tree to any commit");
+    eprintln!("  branch [-d] [<name> [<commit>]]");
+    eprintln!("               - List, create or delete branches");
+    eprintln!("  switch <branch> [--force]");
+    eprintln!("               - Check out a branch so new commits extend it");
+    eprintln!("  tag [-d <name> | <name> [<commit>] [-m <message>]]");
+    eprintln!("               - List tags, or tag a commit; with -m the tag records a message,");
+    eprintln!("                 its author and date");
+    eprintln!("  stash [-m <message>] | stash list | stash pop [<n>] | stash drop [<n>]");
+    eprintln!("               - Put uncommitted changes aside and restore the checked-out commit,");
+    eprintln!("                 list them, bring them back or discard them");
+    eprintln!("  merge <branch> [-m <message>] | --abort");
+    eprintln!("               - Merge another branch into the current one");
+    eprintln!("  log [--all] [--oneline | --format <template>] [--stat] [--graph] [--author <name>]");
+    eprintln!("      [--since <date>] [--until <date>] [[--] <path>...]");
+    eprintln!("               - Show history of HEAD, or of every commit, optionally only commits");
+    eprintln!("                 touching <path>; templates use %H %h %i %p %an %ad %at %s %b %d %n");
+    eprintln!("  blame <file> [<commit>]");
+    eprintln!("               - Show the commit that introduced each line of a file");
+    eprintln!("  diff [-u | -U<n>] [--staged] [--color | --no-color] [<from> [<to>]] [[--] <path>...]");
+    eprintln!("               - Show unstaged changes, staged ones with --staged, or changes");
+    eprintln!("                 since <from> or between two commits");
+    eprintln!("  fsck         - Verify every object, commit hash and the Merkle root");
+    eprintln!("  gc [--keyframes <n>]");
+    eprintln!("               - Remove unused objects and store history as deltas, with a full");
+    eprintln!("                 snapshot every <n> commits (default 16)");
+    eprintln!("  keygen <key-file>");
+    eprintln!("               - Create an Ed25519 signing key and print its public key line");
+    eprintln!("  verify [--keys <file>] [--require-signed]");
+    eprintln!("               - Check commit signatures against trusted keys (.scm/trusted-keys);");
+    eprintln!("                 with --require-signed, unsigned commits fail too");
+    eprintln!("  prove <commit>");
+    eprintln!("               - Print a Merkle proof that a commit is part of the history");
+    eprintln!("  verify-proof <root> <proof-file | ->");
+    eprintln!("               - Check a proof from 'scm prove' against a published Merkle root");
+    eprintln!("  status [--short]");
+    eprintln!("               - Show uncommitted changes; exits with 6 (dirty tree) if there are any");
+    eprintln!("  clone <remote> [<dir>]");
+    eprintln!("               - Copy another repository into a new directory");
+    eprintln!("  pull <remote> - Fetch the commits another repository has and fast-forward branches");
+    eprintln!("  push <remote> [<branch>]");
+    eprintln!("               - Send missing commits and fast-forward a branch in another repository");
+    eprintln!("  serve [--port <n>]");
+    eprintln!("               - Serve this repository to clone, pull and push on http://127.0.0.1:<n>");
+    eprintln!("               (a <remote> is a repository's directory or http://host:port)");
+    eprintln!("Options:");
+    eprintln!("  --json       - Print one JSON object per command, as described in JSON.md");
 }
 
-/// Get all non-hidden files recursively from current directory
+/// Get all non-hidden files recursively from current directory, skipping those matched by
+/// `.scmignore` files
 fn get_all_files() -> Vec<PathBuf> {
-    let mut files = Vec::new();
-    
-    if let Ok(entries) = fs::read_dir(".") {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            let file_name = path.file_name().unwrap().to_string_lossy();
-            
-            // Skip hidden files and SCM file
-            if file_name.starts_with('.') {
-                continue;
-            }
-            
-            if path.is_file() {
-                files.push(path);
-            } else if path.is_dir() {
-                files.extend(get_files_recursive(&path));
-            }
-        }
-    }
-    
+    let mut rules = Vec::new();
+    let mut files = get_files_recursive(Path::new("."), "", &mut rules);
     files.sort();
     files
 }
 
-fn get_files_recursive(dir: &Path) -> Vec<PathBuf> {
+/// Collect the files under `dir`, whose path relative to the repository root is `rel`
+fn get_files_recursive(dir: &Path, rel: &str, rules: &mut Vec<IgnoreRule>) -> Vec<PathBuf> {
     let mut files = Vec::new();
+    // Rules from this directory's ignore file apply only below it
+    let inherited = rules.len();
+    if let Ok(content) = fs::read_to_string(dir.join(IGNORE_FILE)) {
+        rules.extend(content.lines().filter_map(|line| IgnoreRule::parse(line, rel)));
+    }
     
     if let Ok(entries) = fs::read_dir(dir) {
         for entry in entries.flatten() {
             let path = entry.path();
-            let file_name = path.file_name().unwrap().to_string_lossy();
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
             
+            // Skip hidden files and SCM file
             if file_name.starts_with('.') {
                 continue;
             }
+            let entry_rel = if rel.is_empty() { file_name.to_string() } else { format!("{}/{}", rel, file_name) };
+            let is_dir = path.is_dir();
+            if is_ignored(rules, &entry_rel, is_dir) {
+                continue;
+            }
             
             if path.is_file() {
                 files.push(path);
-            } else if path.is_dir() {
-                files.extend(get_files_recursive(&path));
+            } else if is_dir {
+                files.extend(get_files_recursive(&path, &entry_rel, rules));
             }
-        }}
+        }
+    }
+    rules.truncate(inherited);
     files
 }
 
-/// Read file as lines
-fn read_file_lines(path: &Path) -> Vec<String> {
-    fs::read_to_string(path)
-        .unwrap_or_default()
-        .lines()
-        .map(String::from)
-        .collect()
+/// One pattern line of an ignore file
+#[derive(Debug)]
+struct IgnoreRule {
+    /// Directory holding the ignore file, relative to the repository root
+    base: String,
+    pattern: String,
+    /// `!pattern`: re-include paths an earlier rule ignored
+    negated: bool,
+    /// `pattern/`: only match directories
+    dir_only: bool,
+    /// Patterns containing a `/` match from `base`; others match a name at any depth
+    anchored: bool,
 }
 
-/// Compute SHA-512 hash of all files concatenated
-fn compute_hash(files: &HashMap<String, Vec<String>>) -> String {
-    let mut hasher = Sha512::new();
-    
-    // Sort keys for deterministic hashing
-    let mut keys: Vec<_> = files.keys().collect();
-    keys.sort();
-    
-    for key in keys {
-        hasher.update(key.as_bytes());
-        if let Some(lines) = files.get(key) {
-            for line in lines {
-                hasher.update(line.as_bytes());
-                hasher.update(b"\n");
-            }
+impl IgnoreRule {
+    /// Parse a gitignore-style line; blank lines and `#` comments yield nothing
+    fn parse(line: &str, base: &str) -> Option<IgnoreRule> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
         }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let pattern = line.trim_start_matches('/');
+        if pattern.is_empty() {
+            return None;
+        }
+        Some(IgnoreRule { base: base.to_string(), pattern: pattern.to_string(), negated, dir_only, anchored })
     }
     
-    format!("{:x}", hasher.finalize())
-}
-
-/// Generate diff between two versions using the diff implementation
-fn generate_diff(old: &[String], new: &[String]) -> Vec<String> {
-    let lcs_table = build_lcs_table(old, new);
-    let mut result = Vec::new();
-    let mut i = 0;
-    let mut j = 0;
-    
-    while i < old.len() || j < new.len() {
-        if i < old.len() && j < new.len() && old[i] == new[j] {
-            i += 1;
-            j += 1;
-        } else if j >= new.len() || (i < old.len() && lcs_table[i + 1][j] > lcs_table[i][j + 1]) {
-            result.push(format!("{}d{}", i + 1, j));
-            result.push(format!("< {}", old[i]));
-            i += 1;
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let rel = if self.base.is_empty() {
+            path
         } else {
-            result.push(format!("{}a{}", i, j + 1));
-            result.push(format!("> {}", new[j]));
-            j += 1;
+            match path.strip_prefix(&self.base).and_then(|rest| rest.strip_prefix('/')) {
+                Some(rel) => rel,
+                None => return false,
+            }
+        };
+        if self.anchored {
+            glob_match(self.pattern.as_bytes(), rel.as_bytes())
+        } else {
+            let name = rel.rsplit('/').next().unwrap_or(rel);
+            glob_match(self.pattern.as_bytes(), name.as_bytes())
         }
     }
-    
-    result
 }
 
-fn build_lcs_table(v1: &[String], v2: &[String]) -> Vec<Vec<usize>> {
-    let (len1, len2) = (v1.len(), v2.len());
-    let mut table = vec![vec![0; len2 + 1]; len1 + 1];
-    
-    for i in (0..len1).rev() {
-        for j in (0..len2).rev() {
-            table[i][j] = if v1[i] == v2[j] {
-                1 + table[i + 1][j + 1]
-            } else {
-                std::cmp::max(table[i + 1][j], table[i][j + 1])
-            };
-        }
-    }
-    table
+/// Whether the last rule matching `path` ignores it
+fn is_ignored(rules: &[IgnoreRule], path: &str, is_dir: bool) -> bool {
+    rules
+        .iter()
+        .rev()
+        .find(|rule| rule.matches(path, is_dir))
+        .is_some_and(|rule| !rule.negated)
 }
 
-/// Apply diff in reverse to revert changes
-fn apply_diff_reverse(current: &[String], diff: &[String]) -> Vec<String> {
-    let mut result = current.to_vec();
-    
-    // Simple reverse application - in production would parse diff format properly
-    for line in diff.iter().rev() {
-        if line.starts_with("< ") {
-            // Line was deleted, add it back
-            let content = line[2..].to_string();
-            result.push(content);
-        } else if line.starts_with("> ") {
-            // Line was added, remove it
-            let content = line[2..].to_string();
-            result.retain(|l| l != &content);
+/// Match a glob where `*` and `?` stay within one path segment, `**` spans any number of
+/// segments and `[...]` is a character class
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+            // `**/` also matches no directories at all
+            if let Some(after) = rest.strip_prefix(b"/") {
+                if glob_match(after, text) {
+                    return true;
+                }
+            }
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
         }
-    }
-    
-    result
-}
-
-/// Build Merkle tree from commit hashes
-fn build_merkle_tree(hashes: &[String]) -> Vec<Vec<String>> {
-    if hashes.is_empty() {
-        return vec![];}
-    let mut tree = vec![hashes.to_vec()];
-    let mut current_level = hashes.to_vec();
-    while current_level.len() > 1 {
-        let mut next_level = Vec::new();
-        for chunk in current_level.chunks(2) {
-            let combined = if chunk.len() == 2 {
-                format!("{}{}", chunk[0], chunk[1])
-            } else {
-                chunk[0].clone()
+        Some(b'*') => {
+            let segment = text.iter().position(|c| *c == b'/').unwrap_or(text.len());
+            (0..=segment).any(|i| glob_match(&pattern[1..], &text[i..]))
+        }
+        Some(b'?') => !text.is_empty() && text[0] != b'/' && glob_match(&pattern[1..], &text[1..]),
+        Some(b'[') => {
+            let Some(close) = pattern.iter().skip(2).position(|c| *c == b']').map(|i| i + 2) else {
+                return text.first() == Some(&b'[') && glob_match(&pattern[1..], &text[1..]);
+            };
+            let Some(c) = text.first().filter(|c| **c != b'/') else {
+                return false;
             };
-            let mut hasher = Sha512::new();
-            hasher.update(combined.as_bytes());
-            let hash = format!("{:x}", hasher.finalize());
-            next_level.push(hash);
+            let (negated, class) = match pattern[1] {
+                b'!' | b'^' => (true, &pattern[2..close]),
+                _ => (false, &pattern[1..close]),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    found |= (class[i]..=class[i + 2]).contains(c);
+                    i += 3;
+                } else {
+                    found |= class[i] == *c;
+                    i += 1;
+                }
+            }
+            found != negated && glob_match(&pattern[close + 1..], &text[1..])
         }
-        tree.push(next_level.clone());
-        current_level = next_level;
+        Some(b'\\') if pattern.len() > 1 => text.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &text[1..]),
+        Some(c) => text.first() == Some(c) && glob_match(&pattern[1..], &text[1..]),
     }
-    tree
 }
 
-fn commit() {
-    let files = get_all_files();
-    
-    if files.is_empty() {
-        eprintln!("No files to commit");
-        return;
-    }
-    let mut current_files: HashMap<String, Vec<String>> = HashMap::new();
-    for file in &files {
-        let path_str = file.to_string_lossy().to_string();
-        let lines = read_file_lines(file);
-        current_files.insert(path_str, lines);
-    }
-    // Check if .scm exists
-    if !Path::new(SCM_FILE).exists() || fs::metadata(SCM_FILE).unwrap().len() == 0 {
-        // Initialize - first commit
-        println!("Initializing SCM...");
-        
-        let hash = compute_hash(&current_files);
-        let commit = CommitEntry {
-            hash: hash.clone(),
-            init: current_files.clone(),
-            diff: HashMap::new(),
-        };
-        
-        let merkle = build_merkle_tree(&[hash]);
-        let scm_data = ScmData {
-            latest: current_files,
-            commits: vec![commit],
-            merkle,
-        };
-        let json = serde_json::to_string_pretty(&scm_data).unwrap();
-        fs::write(SCM_FILE, json).expect("Failed to write .scm file");
-        println!("Initialized with {} files", files.len());
-    } else {
-        // Load existing SCM data
-        let content = fs::read_to_string(SCM_FILE).expect("Failed to read .scm");
-        let mut scm_data: ScmData = serde_json::from_str(&content).expect("Failed to parse .scm");
-        // Get files that existed in last commit
-        let last_commit = scm_data.commits.last().unwrap();
-        let mut old_files: Vec<String> = last_commit.init.keys().cloned().collect();
-        old_files.extend(last_commit.diff.keys().cloned());
-        // Separate new files from modified files
-        let mut init: HashMap<String, Vec<String>> = HashMap::new();
-        let mut diff: HashMap<String, Vec<String>> = HashMap::new();
-        
-        for (path, lines) in &current_files {
-            if !old_files.contains(path) {
-                // New file
-                init.insert(path.clone(), lines.clone());
-            } else if let Some(old_lines) = scm_data.latest.get(path) {
-                // Check if modified
-                if old_lines != lines {
-                    let file_diff = generate_diff(old_lines, lines);
-                    if !file_diff.is_empty() {
-                        diff.insert(path.clone(), file_diff);
-                    }
-                }
+/// Read file as lines
+fn read_file_lines(path: &Path) -> Result<Vec<String>, ScmError> {
+    fs::read(path).map(file_lines).map_err(io_error(path.display()))
+}
+
+/// Split file contents into lines that `file_bytes` turns back into the exact same bytes:
+/// `\r` stays part of its line, a missing final newline adds a `NO_NEWLINE` line, and content
+/// that is not text becomes a single `BINARY_PREFIX` line
+fn file_lines(bytes: Vec<u8>) -> Vec<String> {
+    let bytes = match String::from_utf8(bytes) {
+        Ok(text) if !text.contains('\0') => {
+            let mut lines: Vec<String> = text.split_terminator('\n').map(String::from).collect();
+            if !text.is_empty() && !text.ends_with('\n') {
+                lines.push(NO_NEWLINE.to_string());
             }
+            return lines;
         }
-        
-        if init.is_empty() && diff.is_empty() {
-            println!("No changes to commit");
-            return;
-        }
-        
-        // Compute hash and create commit
-        let hash = compute_hash(&current_files);
-        let commit = CommitEntry {
-            hash: hash.clone(),
-            init,
-            diff,
-        };
-        
-        scm_data.commits.push(commit);
-        scm_data.latest = current_files;
-        // Update Merkle tree
-        let all_hashes: Vec<String> = scm_data.commits.iter().map(|c| c.hash.clone()).collect();
-        scm_data.merkle = build_merkle_tree(&all_hashes);
-        let json = serde_json::to_string_pretty(&scm_data).unwrap();
-        fs::write(SCM_FILE, json).expect("Failed to write .scm file");
-        
-        println!("Committed changes (hash: {}...)", &hash[..16]);
-    }
+        Ok(text) => text.into_bytes(),
+        Err(e) => e.into_bytes(),
+    };
+    vec![format!("{}{}", BINARY_PREFIX, hex::encode(bytes))]
 }
 
-fn revert() {
-    if !Path::new(SCM_FILE).exists() {
-        eprintln!("No .scm file found. Initialize with 'scm commit' first.");
-        return;
-    }
-    
-    let content = fs::read_to_string(SCM_FILE).expect("Failed to read .scm");
-    let mut scm_data: ScmData = serde_json::from_str(&content).expect("Failed to parse .scm");
-    
-    if scm_data.commits.len() < 2 {
-        eprintln!("No previous commit to revert to");
-        return;
-    }
-    
-    // Remove last commit
-    let removed = scm_data.commits.pop().unwrap();
-    println!("Reverting commit {}...", &removed.hash[..16]);
-    
-    // Reconstruct previous state
-    let mut previous_state: HashMap<String, Vec<String>> = HashMap::new();
-    
-    for commit in &scm_data.commits {
-        // Add init files
-        for (path, lines) in &commit.init {
-            previous_state.insert(path.clone(), lines.clone());
-        }
-        
-        // Apply diffs (simplified - just store latest from diff)
-        for (path, _diff) in &commit.diff {
-            if let Some(lines) = scm_data.latest.get(path) {
-                previous_state.insert(path.clone(), lines.clone());
-            }
+/// Whether lines from `file_lines` hold binary content
+fn is_binary(lines: &[String]) -> bool {
+    matches!(lines, [line] if line.starts_with(BINARY_PREFIX))
+}
+
+/// Read every non-hidden file in the working tree
+fn read_working_tree() -> Result<HashMap<String, Vec<String>>, ScmError> {
+    get_all_files()
+        .iter()
+        .map(|file| Ok((file.to_string_lossy().to_string(), read_file_lines(file)?)))
+        .collect()
+}
+
+/// Bytes of a file as written to the working tree
+fn file_bytes(lines: &[String]) -> Vec<u8> {
+    if let [line] = lines {
+        if let Some(Ok(bytes)) = line.strip_prefix(BINARY_PREFIX).map(hex::decode) {
+            return bytes;
         }
     }
-    
-    // Write previous state back to filesystem
-    for (path, lines) in &previous_state {
-        let content = lines.join("\n") + "\n";
+    // A merge may have moved the no-newline marker away from the end; it is never content
+    let text: Vec<&str> = lines.iter().map(String::as_str).filter(|line| *line != NO_NEWLINE).collect();
+    let mut bytes = text.join("\n").into_bytes();
+    if !text.is_empty() && lines.last().is_some_and(|line| line != NO_NEWLINE) {
+        bytes.push(b'\n');
+    }
+    bytes
+}
+
+/// Write files to the working tree, creating parent directories as needed
+fn write_files(files: &HashMap<String, Vec<String>>) -> Result<(), ScmError> {
+    for (path, lines) in files {
         if let Some(parent) = Path::new(path).parent() {
-            fs::create_dir_all(parent).ok();
+            fs::create_dir_all(parent).map_err(io_error(parent.display()))?;
         }
-        fs::write(path, content).expect("Failed to write file");
+        fs::write(path, file_bytes(lines)).map_err(io_error(display_path(path)))?;
     }
-    
-    scm_data.latest = previous_state;
-    // Update Merkle tree
-    let all_hashes: Vec<String> = scm_data.commits.iter().map(|c| c.hash.clone()).collect();
-    scm_data.merkle = build_merkle_tree(&all_hashes);
-    let json = serde_json::to_string_pretty(&scm_data).unwrap();
-    fs::write(SCM_FILE, json).expect("Failed to write .scm file");
-    println!("Reverted to previous commit");
+    Ok(())
 }
 
-fn log() {
-    if !Path::new(SCM_FILE).exists() {
-        eprintln!("No .scm file found");
-        return;
-    }
-    
-    let content = fs::read_to_string(SCM_FILE).expect("Failed to read .scm");
-    let scm_data: ScmData = serde_json::from_str(&content).expect("Failed to parse .scm");
-    println!("Commit History:");
-    println!("==============");
-    for (idx, commit) in scm_data.commits.iter().enumerate().rev() {
-        println!("\nCommit #{}", idx);
-        println!("Hash: {}", commit.hash);
-        println!("New files: {}", commit.init.len());
-        println!("Modified files: {}", commit.diff.len());
-    }
-    if !scm_data.merkle.is_empty() {
-        let root_level = scm_data.merkle.last().unwrap();
-        if !root_level.is_empty() {
-            println!("\nMerkle Root: {}", root_level[0]);
+/// Remove a file and any directories it leaves empty
+fn remove_file(path: &str) {
+    fs::remove_file(path).ok();
+    let mut parent = Path::new(path).parent();
+    while let Some(dir) = parent {
+        if dir.as_os_str().is_empty() || dir == Path::new(".") || fs::remove_dir(dir).is_err() {
+            break;
         }
+        parent = dir.parent();
     }
 }
 
-fn status() {
-    if !Path::new(SCM_FILE).exists() {
-        println!("Not under version control. Run 'scm init' to initialize.");
-        return;
+/// Path of an entry inside the `.scm/` directory
+fn scm_path(name: &str) -> PathBuf {
+    Path::new(SCM_DIR).join(name)
+}
+
+/// SHA-512 of an object's bytes, which is also its name in the store
+fn object_id(bytes: &[u8]) -> String {
+    let mut hasher = Sha512::new();
+    hasher.update(bytes);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Objects are fanned out by the first two hex digits of their id
+fn object_path(id: &str) -> PathBuf {
+    store_path("objects", id)
+}
+
+/// Where `scm gc` puts an object it stores as a delta against another
+fn delta_path(id: &str) -> PathBuf {
+    store_path("deltas", id)
+}
+
+fn store_path(store: &str, id: &str) -> PathBuf {
+    let split = if id.is_char_boundary(2) { 2 } else { 0 };
+    scm_path(store).join(&id[..split]).join(&id[split..])
+}
+
+/// Whether an object is stored, in full or as a delta
+fn has_object(id: &str) -> bool {
+    object_path(id).exists() || delta_path(id).exists()
+}
+
+/// Write a file into one of the stores, creating its fan-out directory
+fn write_stored(path: &Path, bytes: &[u8]) -> Result<(), ScmError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(io_error(dir.display()))?;
     }
-    let content = fs::read_to_string(SCM_FILE).expect("Failed to read .scm");
-    let scm_data: ScmData = serde_json::from_str(&content).expect("Failed to parse .scm");
-    println!("SCM Status:");
-    println!("Total commits: {}", scm_data.commits.len());
-    println!("Tracked files: {}", scm_data.latest.len());
-    if let Some(last) = scm_data.commits.last() {
-        println!("Commit hash: {}...", &last.hash[..16]);
+    write_atomic(path, bytes)
+}
+
+/// Store an object unless an identical one is already present
+fn write_object(bytes: &[u8]) -> Result<String, ScmError> {
+    let id = object_id(bytes);
+    if !has_object(&id) {
+        write_stored(&object_path(&id), bytes)?;
     }
-}
\ No newline at end of file
+    Ok(id)
+}
+
+/// Read an object; a missing object means history has been damaged
+fn read_object(id: &str) -> Result<Vec<u8>, ScmError> {
+    load_object(Path::new(""), id)
+}
+
+/// Object stored by `scm gc` as the changes from another object
+#[derive(Serialize, Deserialize, Debug)]
+struct Delta {
+    base: String,
+    ops: Vec<DeltaOp>,
+}
+
+/// Step in rebuilding an object from its delta base
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum DeltaOp {
+    /// Bytes `start..start + len` of the base
+    Copy(usize, usize),
+    Insert(String),
+}
+
+/// Read an object from the store of the repository at `root`, following the chain of deltas
+/// down to a full object when `scm gc` has stored it as one
+fn load_object(root: &Path, id: &str) -> Result<Vec<u8>, ScmError> {
+    let mut chain: Vec<(String, Delta)> = Vec::new();
+    let mut id = id.to_string();
+    let mut bytes = loop {
+        let path = root.join(object_path(&id));
+        match fs::read(&path) {
+            Ok(bytes) => break bytes,
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(ScmError::Io(format!("{}: {}", path.display(), e)));
+            }
+            Err(_) => {}
+        }
+        let delta_file = root.join(delta_path(&id));
+        let delta: Delta = match fs::read(&delta_file) {
+            Ok(bytes) => parse_json(&bytes, &delta_file.display().to_string())?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(ScmError::CorruptHistory(format!("object {} is missing", path.display())));
+            }
+            Err(e) => return Err(ScmError::Io(format!("{}: {}", delta_file.display(), e))),
+        };
+        if !is_object_id(&delta.base) || delta.base == id || chain.iter().any(|(seen, _)| *seen == delta.base) {
+            return Err(ScmError::CorruptHistory(format!("{} has an invalid base", delta_file.display())));
+        }
+        let base = delta.base.clone();
+        chain.push((id, delta));
+        id = base;
+    };
+    for (id, delta) in chain.iter().rev() {
+        bytes = apply_delta(&bytes, &delta.ops).ok_or_else(|| {
+            ScmError::CorruptHistory(format!("{} does not fit its base", root.join(delta_path(id)).display()))
+        })?;
+    }
+    Ok(bytes)
+}
+
+/// Pieces compared when delta-encoding an object: lines, split again after commas so that the
+/// entries of a tree line up
+fn delta_chunks(text: &str) -> Vec<String> {
+    text.split_inclusive(['\n', ',']).map(String::from).collect()
+}
+
+/// Steps rebuilding `target` from `base`, or `None` unless both are text
+fn make_delta(base: &[u8], target: &[u8]) -> Option<Vec<DeltaOp>> {
+    let old = delta_chunks(std::str::from_utf8(base).ok()?);
+    let new = delta_chunks(std::str::from_utf8(target).ok()?);
+    // Only the middle needs aligning, which keeps the LCS table small for large trees
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (old_end, new_end) = (old.len() - suffix, new.len() - suffix);
+    let mut steps: Vec<(char, usize, usize)> = (0..prefix).map(|k| (' ', k, k)).collect();
+    let middle = edit_script(&old[prefix..old_end], &new[prefix..new_end]);
+    steps.extend(middle.into_iter().map(|(op, i, j)| (op, i + prefix, j + prefix)));
+    steps.extend((0..suffix).map(|k| (' ', old_end + k, new_end + k)));
+    let offsets: Vec<usize> = old
+        .iter()
+        .scan(0, |pos, chunk| {
+            *pos += chunk.len();
+            Some(*pos - chunk.len())
+        })
+        .collect();
+    let mut ops = Vec::new();
+    for (op, i, j) in steps {
+        match (op, ops.last_mut()) {
+            (' ', Some(DeltaOp::Copy(start, len))) if *start + *len == offsets[i] => *len += old[i].len(),
+            (' ', _) => ops.push(DeltaOp::Copy(offsets[i], old[i].len())),
+            ('+', Some(DeltaOp::Insert(text))) => text.push_str(&new[j]),
+            ('+', _) => ops.push(DeltaOp::Insert(new[j].clone())),
+            _ => {}
+        }
+    }
+    Some(ops)
+}
+
+fn apply_delta(base: &[u8], ops: &[DeltaOp]) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for op in ops {
+        match op {
+            DeltaOp::Copy(start, len) => bytes.extend_from_slice(base.get(*start..start.checked_add(*len)?)?),
+            DeltaOp::Insert(text) => bytes.extend_from_slice(text.as_bytes()),
+        }
+    }
+    Some(bytes)
+}
+
+/// Blob id a file's lines would be stored under
+fn blob_id(lines: &[String]) -> String {
+    object_id(&file_bytes(lines))
+}
+
+fn write_blob(lines: &[String]) -> Result<String, ScmError> {
+    write_object(&file_bytes(lines))
+}
+
+fn read_blob(id: &str) -> Result<Vec<String>, ScmError> {
+    Ok(file_lines(read_object(id)?))
+}
+
+/// Store every file as a blob plus a tree object mapping paths to blob ids
+fn write_tree(files: &HashMap<String, Vec<String>>) -> Result<String, ScmError> {
+    let tree = files
+        .iter()
+        .map(|(path, lines)| Ok((path, write_blob(lines)?)))
+        .collect::<Result<BTreeMap<&String, String>, ScmError>>()?;
+    write_object(&to_json(&tree))
+}
+
+fn read_tree(id: &str) -> Result<HashMap<String, Vec<String>>, ScmError> {
+    let bytes = read_object(id)?;
+    let tree: BTreeMap<String, String> = parse_json(&bytes, &format!("tree {}", object_path(id).display()))?;
+    tree.into_iter().map(|(path, blob)| Ok((path, read_blob(&blob)?))).collect()
+}
+
+fn write_commit(commit: &CommitEntry) -> Result<String, ScmError> {
+    write_object(&to_json(commit))
+}
+
+fn read_commit(id: &str) -> Result<CommitEntry, ScmError> {
+    let bytes = read_object(id)?;
+    parse_json(&bytes, &format!("commit {}", object_path(id).display()))
+}
+
+/// Serialize a value to compact JSON; the repository's types always serialize
+fn to_json<T: Serialize + ?Sized>(value: &T) -> Vec<u8> {
+    serde_json::to_vec(value).unwrap_or_default()
+}
+
+/// Parse JSON, naming `what` was being read if it is malformed
+fn parse_json<T: serde::de::DeserializeOwned>(bytes: &[u8], what: &str) -> Result<T, ScmError> {
+    serde_json::from_slice(bytes).map_err(|e| ScmError::Parse(format!("{}: {}", what, e)))
+}
+
+/// Read an object, checking that its content still hashes to its id
+fn read_verified(id: &str) -> Result<Vec<u8>, ScmError> {
+    let bytes = read_object(id)?;
+    let actual = object_id(&bytes);
+    if actual != id {
+        let path = if object_path(id).exists() { object_path(id) } else { delta_path(id) };
+        return Err(ScmError::CorruptHistory(format!("{} holds content hashing to {}", path.display(), actual)));
+    }
+    Ok(bytes)
+}
+
+/// Read a tree and its blobs, checking every object against its id
+fn read_verified_tree(id: &str) -> Result<HashMap<String, Vec<String>>, ScmError> {
+    let bytes = read_verified(id)?;
+    let tree: BTreeMap<String, String> = parse_json(&bytes, &object_path(id).display().to_string())?;
+    tree.into_iter()
+        .map(|(path, blob)| {
+            let bytes = read_verified(&blob).map_err(|e| {
+                ScmError::CorruptHistory(format!("blob of {}: {}", display_path(&path), e))
+            })?;
+            Ok((path, file_lines(bytes)))
+        })
+        .collect()
+}
+
+/// Line of `.scm/commits` for a commit: its object id, then the hash, tree and parents, which
+/// is all most commands need to know about history without reading commit objects
+fn commit_list_line(id: &str, commit: &CommitEntry) -> String {
+    let tree = if commit.tree.is_empty() { "-" } else { &commit.tree };
+    let mut line = format!("{} {} {}", id, commit.hash, tree);
+    for parent in &commit.parents {
+        line.push(' ');
+        line.push_str(parent);
+    }
+    line.push('\n');
+    line
+}
+
+/// Add the commits not yet in `.scm/commits` to its end, or write it whole if it is new or in
+/// the older format. A line only counts once its newline is on disk, so an append cut short
+/// leaves a partial line that loading ignores and the next append replaces
+fn write_commit_list(scm_data: &mut ScmData) -> Result<(), ScmError> {
+    let path = scm_path("commits");
+    let lines = |from: usize| -> String {
+        (from..scm_data.ids.len()).map(|idx| commit_list_line(&scm_data.ids[idx], &scm_data.commits[idx])).collect()
+    };
+    match scm_data.listed {
+        Some(listed) => {
+            let mut file = fs::OpenOptions::new().read(true).write(true).open(&path).map_err(io_error(path.display()))?;
+            let mut list = Vec::new();
+            let length = file.seek(SeekFrom::End(0)).map_err(io_error(path.display()))?;
+            let mut last = [b'\n'];
+            if length > 0 {
+                file.seek(SeekFrom::End(-1)).and_then(|_| file.read_exact(&mut last)).map_err(io_error(path.display()))?;
+            }
+            let keep = if last[0] == b'\n' {
+                length
+            } else {
+                file.seek(SeekFrom::Start(0)).and_then(|_| file.read_to_end(&mut list)).map_err(io_error(path.display()))?;
+                list.iter().rposition(|b| *b == b'\n').map_or(0, |end| end as u64 + 1)
+            };
+            file.set_len(keep)
+                .and_then(|_| file.seek(SeekFrom::Start(keep)))
+                .and_then(|_| file.write_all(lines(listed).as_bytes()))
+                .and_then(|_| file.sync_all())
+                .map_err(io_error(path.display()))?;
+        }
+        None => write_atomic(&path, lines(0).as_bytes())?,
+    }
+    scm_data.listed = Some(scm_data.ids.len());
+    Ok(())
+}
+
+/// Record a new commit on top of the loaded history
+fn push_commit(scm_data: &mut ScmData, commit: CommitEntry) -> Result<(), ScmError> {
+    let id = write_commit(&commit)?;
+    scm_data.ids.push(id);
+    scm_data.commits.push(commit);
+    write_commit_list(scm_data)?;
+    // Update Merkle tree
+    let all_hashes: Vec<String> = scm_data.commits.iter().map(|c| c.hash.clone()).collect();
+    scm_data.merkle = build_merkle_tree(&all_hashes);
+    Ok(())
+}
+
+fn load_scm() -> Result<ScmData, ScmError> {
+    if Path::new(SCM_DIR).is_file() {
+        migrate_legacy()?;
+    }
+    let list_path = scm_path("commits");
+    let list = fs::read_to_string(&list_path).map_err(io_error(list_path.display()))?;
+    let mut ids = Vec::new();
+    let mut commits = Vec::new();
+    let mut summarized = true;
+    for line in list.split_inclusive('\n').filter(|line| line.ends_with('\n')) {
+        let mut fields = line.split_whitespace();
+        let Some(id) = fields.next() else {
+            return Err(ScmError::CorruptHistory(format!("{}: line {} is empty", list_path.display(), ids.len() + 1)));
+        };
+        commits.push(match fields.next() {
+            // Lists from before summaries were kept name only the object
+            None => {
+                summarized = false;
+                read_commit(id)?
+            }
+            Some(hash) => CommitEntry {
+                hash: hash.to_string(),
+                tree: fields.next().filter(|tree| *tree != "-").unwrap_or_default().to_string(),
+                parents: fields.map(String::from).collect(),
+                ..CommitEntry::default()
+            },
+        });
+        ids.push(id.to_string());
+    }
+    if commits.is_empty() {
+        return Err(ScmError::CorruptHistory(format!("{} lists no commits", list_path.display())));
+    }
+    let state: RepoState = match fs::read(scm_path("state")) {
+        Ok(bytes) => parse_json(&bytes, ".scm/state")?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => RepoState::default(),
+        Err(e) => return Err(ScmError::Io(format!(".scm/state: {}", e))),
+    };
+    if let Some(head) = state.head.filter(|head| *head >= commits.len()) {
+        return Err(ScmError::CorruptHistory(format!(".scm/state: HEAD is commit #{}, which does not exist", head)));
+    }
+    let all_hashes: Vec<String> = commits.iter().map(|c| c.hash.clone()).collect();
+    let mut scm_data = ScmData {
+        latest: HashMap::new(),
+        commits,
+        merkle: build_merkle_tree(&all_hashes),
+        head: state.head,
+        branch: state.branch,
+        branches: state.branches,
+        merging: state.merging,
+        merge_added: state.merge_added,
+        conflicts: state.conflicts,
+        listed: if summarized { Some(ids.len()) } else { None },
+        history_loaded: !summarized,
+        ids,
+        index: state.index,
+        tags: state.tags,
+    };
+    // Repositories from before branches existed have one line of history
+    if scm_data.branches.is_empty() {
+        scm_data.branch = DEFAULT_BRANCH.to_string();
+        if let Some(last) = scm_data.commits.last() {
+            scm_data.branches.insert(DEFAULT_BRANCH.to_string(), last.hash.clone());
+        }
+    }
+    let head = head_index(&scm_data);
+    scm_data.latest = reconstruct(&scm_data, head)?;
+    Ok(scm_data)
+}
+
+/// Read the full commit objects behind the summaries `load_scm` keeps, for commands that show
+/// or walk messages, authors, signatures or diffs
+fn load_history(scm_data: &mut ScmData) -> Result<(), ScmError> {
+    if scm_data.history_loaded {
+        return Ok(());
+    }
+    for idx in 0..scm_data.commits.len() {
+        let commit = full_commit(scm_data, idx)?.into_owned();
+        scm_data.commits[idx] = commit;
+    }
+    scm_data.history_loaded = true;
+    Ok(())
+}
+
+/// One full commit, read from its object unless the history is loaded; the object must hold
+/// the commit `.scm/commits` says it does
+fn full_commit(scm_data: &ScmData, index: usize) -> Result<Cow<'_, CommitEntry>, ScmError> {
+    if scm_data.history_loaded {
+        return Ok(Cow::Borrowed(&scm_data.commits[index]));
+    }
+    let commit = read_commit(&scm_data.ids[index])?;
+    if commit.hash != scm_data.commits[index].hash {
+        return Err(ScmError::CorruptHistory(format!(
+            ".scm/commits: line {} lists commit {} but its object holds {}",
+            index + 1,
+            short(&scm_data.commits[index].hash),
+            short(&commit.hash)
+        )));
+    }
+    Ok(Cow::Owned(commit))
+}
+
+/// Write the checked-out commit and Merkle root; commits themselves are written by `push_commit`
+fn save_scm(scm_data: &ScmData) -> Result<(), ScmError> {
+    let state = RepoState {
+        head: scm_data.head,
+        branch: scm_data.branch.clone(),
+        branches: scm_data.branches.clone(),
+        merging: scm_data.merging.clone(),
+        merge_added: scm_data.merge_added.clone(),
+        conflicts: scm_data.conflicts.clone(),
+        merkle_root: merkle_root(&scm_data.merkle),
+        index: scm_data.index.clone(),
+        tags: scm_data.tags.clone(),
+    };
+    let json = serde_json::to_vec_pretty(&state).unwrap_or_default();
+    write_atomic(&scm_path("state"), &json)
+}
+
+/// Convert a single-file `.scm` into the object store, keeping the original as a backup. The
+/// new `.scm/` is built aside and only replaces the file once every commit has been replayed
+fn migrate_legacy() -> Result<(), ScmError> {
+    // The repository lock lives in `.scm/`, which does not exist yet
+    match fs::create_dir(MIGRATING_DIR) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            return Err(ScmError::Locked(format!(
+                "Repository being migrated by another scm command; if none is running, remove {}",
+                MIGRATING_DIR
+            )));
+        }
+        Err(e) => return Err(ScmError::Io(format!("{}: {}", MIGRATING_DIR, e))),
+    }
+    // Another command may have finished migrating while this one waited to start
+    let result = if Path::new(SCM_DIR).is_file() { build_migrated() } else { Ok(0) };
+    let result = result.and_then(|count| {
+        if count > 0 {
+            fs::rename(SCM_DIR, LEGACY_BACKUP).map_err(io_error(format!("moving {} to {}", SCM_DIR, LEGACY_BACKUP)))?;
+            let built = Path::new(MIGRATING_DIR).join(SCM_DIR);
+            fs::rename(&built, SCM_DIR).map_err(io_error(format!("moving {} to {}", built.display(), SCM_DIR)))?;
+            say(format!("Migrated {} commits to .scm/ (old file kept as {})", count, LEGACY_BACKUP));
+        }
+        Ok(())
+    });
+    fs::remove_dir_all(MIGRATING_DIR).ok();
+    result
+}
+
+/// Replay the single-file `.scm` into a new `.scm/` inside `MIGRATING_DIR`, returning the number
+/// of commits
+fn build_migrated() -> Result<usize, ScmError> {
+    let content = fs::read(SCM_DIR).map_err(io_error(SCM_DIR))?;
+    let legacy: LegacyScmData = parse_json(&content, SCM_DIR)?;
+    in_repo(Path::new(MIGRATING_DIR), || {
+        fs::create_dir_all(scm_path("objects")).map_err(io_error(SCM_DIR))?;
+        let mut scm_data = ScmData {
+            latest: HashMap::new(),
+            commits: Vec::new(),
+            merkle: Vec::new(),
+            head: legacy.head,
+            branch: String::new(),
+            branches: BTreeMap::new(),
+            merging: None,
+            merge_added: Vec::new(),
+            conflicts: Vec::new(),
+            ids: Vec::new(),
+            index: None,
+            tags: BTreeMap::new(),
+            listed: None,
+            history_loaded: true,
+        };
+        let mut state: HashMap<String, Vec<String>> = HashMap::new();
+        for (idx, old) in legacy.commits.into_iter().enumerate() {
+            let init = old
+                .init
+                .iter()
+                .map(|(path, lines)| Ok((path.clone(), write_blob(lines)?)))
+                .collect::<Result<HashMap<String, String>, ScmError>>()?;
+            let mut commit = CommitEntry {
+                hash: old.hash,
+                parents: Vec::new(),
+                tree: String::new(),
+                staged: String::new(),
+                init,
+                diff: old.diff,
+                deleted: old.deleted,
+                renamed: old.renamed,
+                message: String::new(),
+                author: String::new(),
+                timestamp: None,
+                signature: None,
+            };
+            apply_commit(&mut state, &commit, idx)
+                .map_err(|e| ScmError::CorruptHistory(format!("cannot migrate {}: {}", SCM_DIR, e)))?;
+            commit.tree = write_tree(&state)?;
+            scm_data.ids.push(write_commit(&commit)?);
+            scm_data.commits.push(commit);
+        }
+        if scm_data.commits.is_empty() {
+            return Err(ScmError::CorruptHistory(format!("cannot migrate {}: it holds no commits", SCM_DIR)));
+        }
+        let hashes: Vec<String> = scm_data.commits.iter().map(|c| c.hash.clone()).collect();
+        scm_data.merkle = build_merkle_tree(&hashes);
+        write_commit_list(&mut scm_data)?;
+        save_scm(&scm_data)?;
+        Ok(scm_data.commits.len())
+    })
+}
+
+/// Index of the newest commit with the given hash
+fn index_of(scm_data: &ScmData, hash: &str) -> Option<usize> {
+    scm_data.commits.iter().rposition(|commit| commit.hash == hash)
+}
+
+/// Index of the commit a branch points at
+fn branch_index(scm_data: &ScmData, name: &str) -> Option<usize> {
+    scm_data.branches.get(name).and_then(|hash| index_of(scm_data, hash))
+}
+
+/// Index of the commit the working tree was last synced with
+fn head_index(scm_data: &ScmData) -> usize {
+    scm_data
+        .head
+        .or_else(|| branch_index(scm_data, &scm_data.branch))
+        .unwrap_or(scm_data.commits.len() - 1)
+}
+
+/// Indices of a commit's parents, first parent first
+fn parent_indices(scm_data: &ScmData, index: usize) -> Vec<usize> {
+    let commit = &scm_data.commits[index];
+    if commit.parents.is_empty() {
+        // Commits from before branches existed follow the previous one
+        return if index > 0 { vec![index - 1] } else { Vec::new() };
+    }
+    commit
+        .parents
+        .iter()
+        .filter_map(|hash| scm_data.commits[..index].iter().rposition(|c| &c.hash == hash))
+        .collect()
+}
+
+fn first_parent(scm_data: &ScmData, index: usize) -> Option<usize> {
+    parent_indices(scm_data, index).first().copied()
+}
+
+/// Every commit reachable from `index` through parents, including itself
+fn ancestors(scm_data: &ScmData, index: usize) -> Vec<usize> {
+    let mut seen = vec![false; scm_data.commits.len()];
+    let mut stack = vec![index];
+    while let Some(idx) = stack.pop() {
+        if !seen[idx] {
+            seen[idx] = true;
+            stack.extend(parent_indices(scm_data, idx));
+        }
+    }
+    (0..seen.len()).filter(|idx| seen[*idx]).collect()
+}
+
+/// Compute SHA-512 hash of a commit's parent hashes and all files concatenated
+fn compute_hash(commit: &CommitEntry, files: &HashMap<String, Vec<String>>) -> String {
+    let mut hasher = Sha512::new();
+    
+    for parent in &commit.parents {
+        hasher.update(parent.as_bytes());
+    }
+    // Commits from before messages existed hash exactly as they did then
+    if !commit.message.is_empty() || !commit.author.is_empty() || commit.timestamp.is_some() {
+        hasher.update(b"author ");
+        hasher.update(commit.author.as_bytes());
+        hasher.update(format!("\ntime {}\n\n", commit.timestamp.unwrap_or(0)).as_bytes());
+        hasher.update(commit.message.as_bytes());
+        hasher.update(b"\n\0");
+    }
+    
+    // Sort keys for deterministic hashing
+    let mut keys: Vec<_> = files.keys().collect();
+    keys.sort();
+    
+    for key in keys {
+        hasher.update(key.as_bytes());
+        if let Some(lines) = files.get(key) {
+            for line in lines {
+                hasher.update(line.as_bytes());
+                hasher.update(b"\n");
+            }
+        }
+    }
+    
+    format!("{:x}", hasher.finalize())
+}
+
+/// Generate diff between two versions using the diff implementation
+fn generate_diff(old: &[String], new: &[String]) -> Vec<String> {
+    let lcs_table = build_lcs_table(old, new);
+    let mut result = Vec::new();
+    let mut i = 0;
+    let mut j = 0;
+    
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j >= new.len() || (i < old.len() && lcs_table[i + 1][j] > lcs_table[i][j + 1]) {
+            result.push(format!("{}d{}", i + 1, j));
+            result.push(format!("< {}", old[i]));
+            i += 1;
+        } else {
+            result.push(format!("{}a{}", i, j + 1));
+            result.push(format!("> {}", new[j]));
+            j += 1;
+        }
+    }
+    
+    result
+}
+
+fn build_lcs_table(v1: &[String], v2: &[String]) -> Vec<Vec<usize>> {
+    let (len1, len2) = (v1.len(), v2.len());
+    let mut table = vec![vec![0; len2 + 1]; len1 + 1];
+    
+    for i in (0..len1).rev() {
+        for j in (0..len2).rev() {
+            table[i][j] = if v1[i] == v2[j] {
+                1 + table[i + 1][j + 1]
+            } else {
+                std::cmp::max(table[i + 1][j], table[i][j + 1])
+            };
+        }
+    }
+    table
+}
+
+/// A run of deleted or added lines parsed from a `NdM`/`NaM` header
+#[derive(Debug, Clone)]
+enum Hunk {
+    /// Old lines starting at `old` (1-based) were removed; `new` lines precede them in the result
+    Delete { old: usize, new: usize, lines: Vec<String> },
+    /// New lines starting at `new` (1-based) were inserted after old line `old`
+    Add { old: usize, new: usize, lines: Vec<String> },
+}
+
+/// Parse the start of a `N` or `N,M` line range
+fn parse_range_start(range: &str) -> Option<usize> {
+    range.split(',').next()?.parse().ok()
+}
+
+/// Parse diff output from `generate_diff` into positional hunks
+fn parse_diff(diff: &[String]) -> Result<Vec<Hunk>, String> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+    for (idx, line) in diff.iter().enumerate() {
+        if let Some(content) = line.strip_prefix("< ") {
+            match hunks.last_mut() {
+                Some(Hunk::Delete { lines, .. }) => lines.push(content.to_string()),
+                _ => return Err(format!("diff line {}: '<' outside a delete hunk", idx + 1)),
+            }
+        } else if let Some(content) = line.strip_prefix("> ") {
+            match hunks.last_mut() {
+                Some(Hunk::Add { lines, .. }) => lines.push(content.to_string()),
+                _ => return Err(format!("diff line {}: '>' outside an add hunk", idx + 1)),
+            }
+        } else {
+            let op = line
+                .find(['a', 'd'])
+                .ok_or_else(|| format!("diff line {}: bad hunk header '{}'", idx + 1, line))?;
+            let old = parse_range_start(&line[..op]);
+            let new = parse_range_start(&line[op + 1..]);
+            let (old, new) = match (old, new) {
+                (Some(old), Some(new)) => (old, new),
+                _ => return Err(format!("diff line {}: bad hunk header '{}'", idx + 1, line)),
+            };
+            let lines = Vec::new();
+            hunks.push(if line.as_bytes()[op] == b'd' {
+                Hunk::Delete { old, new, lines }
+            } else {
+                Hunk::Add { old, new, lines }
+            });
+        }
+    }
+    Ok(hunks)
+}
+
+/// Swap the old and new sides of each hunk so it undoes the original change
+fn reverse_hunks(hunks: Vec<Hunk>) -> Vec<Hunk> {
+    hunks
+        .into_iter()
+        .map(|hunk| match hunk {
+            Hunk::Delete { old, new, lines } => Hunk::Add { old: new, new: old, lines },
+            Hunk::Add { old, new, lines } => Hunk::Delete { old: new, new: old, lines },
+        })
+        .collect()
+}
+
+/// Apply hunks by position, checking that deleted lines and line numbers match
+fn apply_hunks(old_lines: &[String], hunks: &[Hunk]) -> Result<Vec<String>, String> {
+    let mut result = Vec::with_capacity(old_lines.len());
+    let mut cursor = 0;
+    for hunk in hunks {
+        let (old, new, lines) = match hunk {
+            Hunk::Delete { old, new, lines } => (old.saturating_sub(1), *new, lines),
+            Hunk::Add { old, new, lines } => (*old, new.saturating_sub(1), lines),
+        };
+        if old < cursor || old > old_lines.len() {
+            return Err(format!("hunk at old line {} is out of order or out of range", old));
+        }
+        result.extend_from_slice(&old_lines[cursor..old]);
+        cursor = old;
+        if result.len() != new {
+            return Err(format!("hunk expects new line {} but patch is at line {}", new, result.len()));
+        }
+        match hunk {
+            Hunk::Delete { .. } => {
+                let end = cursor + lines.len();
+                if end > old_lines.len() || old_lines[cursor..end] != lines[..] {
+                    return Err(format!("deleted lines at {} do not match the file", cursor + 1));
+                }
+                cursor = end;
+            }
+            Hunk::Add { .. } => result.extend(lines.iter().cloned()),
+        }
+    }
+    result.extend_from_slice(&old_lines[cursor..]);
+    Ok(result)
+}
+
+/// Apply a diff forward, turning the old version into the new one
+fn apply_diff(old: &[String], diff: &[String]) -> Result<Vec<String>, String> {
+    apply_hunks(old, &parse_diff(diff)?)
+}
+
+/// Apply a diff in reverse, turning the new version back into the old one
+fn apply_diff_reverse(new: &[String], diff: &[String]) -> Result<Vec<String>, String> {
+    apply_hunks(new, &reverse_hunks(parse_diff(diff)?))
+}
+
+/// Old and new blob ids of a diff that replaces a binary file whole
+fn blob_change(diff: &[String]) -> Option<(&str, &str)> {
+    match diff {
+        [line] => line.strip_prefix(BLOB_CHANGE)?.split_once(' '),
+        _ => None,
+    }
+}
+
+/// Apply one file's diff from a commit, forward or in reverse, reading the other version of a
+/// binary file from its blob
+fn apply_file_diff(lines: &[String], diff: &[String], reverse: bool) -> Result<Vec<String>, String> {
+    let Some((old, new)) = blob_change(diff) else {
+        return if reverse { apply_diff_reverse(lines, diff) } else { apply_diff(lines, diff) };
+    };
+    let (from, to) = if reverse { (new, old) } else { (old, new) };
+    if blob_id(lines) != from {
+        return Err(format!("file is not blob {}", short(from)));
+    }
+    read_blob(to).map_err(|e| e.to_string())
+}
+
+/// Line-by-line alignment of `old` and `new` from the hunks of `generate_diff`: `' '` for a
+/// line kept from old index `i` at new index `j`, `'-'` for old line `i` removed and `'+'` for
+/// new line `j` added
+fn edit_script(old: &[String], new: &[String]) -> Vec<(char, usize, usize)> {
+    // `generate_diff` output always parses
+    let hunks = parse_diff(&generate_diff(old, new)).unwrap_or_default();
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for hunk in hunks {
+        let (keep_until, removed, added) = match hunk {
+            Hunk::Delete { old, lines, .. } => (old - 1, lines.len(), 0),
+            Hunk::Add { old, lines, .. } => (old, 0, lines.len()),
+        };
+        while i < keep_until {
+            ops.push((' ', i, j));
+            i += 1;
+            j += 1;
+        }
+        for _ in 0..removed {
+            ops.push(('-', i, j));
+            i += 1;
+        }
+        for _ in 0..added {
+            ops.push(('+', i, j));
+            j += 1;
+        }
+    }
+    while i < old.len() {
+        ops.push((' ', i, j));
+        i += 1;
+        j += 1;
+    }
+    // Show removals before additions within each run of changes, as other diff tools do
+    for run in ops.split_mut(|op| op.0 == ' ') {
+        let Some(&(_, i0, j0)) = run.first() else {
+            continue;
+        };
+        let removed = run.iter().filter(|op| op.0 == '-').count();
+        for (k, op) in run.iter_mut().enumerate() {
+            *op = if k < removed { ('-', i0 + k, j0) } else { ('+', i0 + removed, j0 + k - removed) };
+        }
+    }
+    ops
+}
+
+/// Runs of changed lines as `(old_start, old_end, new_start, new_end)` index ranges
+fn change_blocks(ops: &[(char, usize, usize)]) -> Vec<(usize, usize, usize, usize)> {
+    let mut blocks: Vec<(usize, usize, usize, usize)> = Vec::new();
+    let mut in_block = false;
+    for &(op, i, j) in ops {
+        match op {
+            ' ' => in_block = false,
+            _ => {
+                if !in_block {
+                    blocks.push((i, i, j, j));
+                    in_block = true;
+                }
+                if let Some(block) = blocks.last_mut() {
+                    if op == '-' {
+                        block.1 = i + 1;
+                    } else {
+                        block.3 = j + 1;
+                    }
+                }
+            }
+        }
+    }
+    blocks
+}
+
+/// Lines to compare for display: the missing-newline marker is folded into the last line, so
+/// that adding or removing a final newline shows as a change to that line
+fn display_lines(lines: &[String]) -> Vec<String> {
+    let mut lines = lines.to_vec();
+    if lines.len() > 1 && lines.last().is_some_and(|line| line == NO_NEWLINE) {
+        lines.pop();
+        if let Some(last) = lines.last_mut() {
+            last.push_str(NO_NEWLINE);
+        }
+    }
+    lines
+}
+
+/// Add a line of diff output, noting when it is the last line and lacks a newline
+fn push_line(out: &mut Vec<(Paint, String)>, paint: Paint, prefix: &str, line: &str) {
+    match line.strip_suffix(NO_NEWLINE) {
+        Some(text) => {
+            out.push((paint, format!("{}{}", prefix, text)));
+            out.push((Paint::Plain, "\\ No newline at end of file".to_string()));
+        }
+        None => out.push((paint, format!("{}{}", prefix, line))),
+    }
+}
+
+/// 1-based line range in the `diff` tool's `N` or `N,M` notation; empty ranges name the line
+/// before them
+fn normal_range(start: usize, end: usize) -> String {
+    match end - start {
+        0 => start.to_string(),
+        1 => end.to_string(),
+        _ => format!("{},{}", start + 1, end),
+    }
+}
+
+/// Changes in the classic `diff` format: `NaM`, `NdM` and `NcM` blocks with `<`/`>` lines
+fn normal_diff(old: &[String], new: &[String]) -> Vec<(Paint, String)> {
+    let mut out = Vec::new();
+    for (i0, i1, j0, j1) in change_blocks(&edit_script(old, new)) {
+        let op = if i0 == i1 { 'a' } else if j0 == j1 { 'd' } else { 'c' };
+        out.push((Paint::Range, format!("{}{}{}", normal_range(i0, i1), op, normal_range(j0, j1))));
+        for line in &old[i0..i1] {
+            push_line(&mut out, Paint::Removed, "< ", line);
+        }
+        if op == 'c' {
+            out.push((Paint::Plain, "---".to_string()));
+        }
+        for line in &new[j0..j1] {
+            push_line(&mut out, Paint::Added, "> ", line);
+        }
+    }
+    out
+}
+
+/// `start,count` in unified hunk headers, where an empty range names the line before it
+fn unified_range(start: usize, count: usize) -> String {
+    let start = if count == 0 { start } else { start + 1 };
+    if count == 1 {
+        start.to_string()
+    } else {
+        format!("{},{}", start, count)
+    }
+}
+
+/// Changes as `@@` hunks with `context` unchanged lines around each run of changes
+fn unified_diff(old: &[String], new: &[String], context: usize) -> Vec<(Paint, String)> {
+    let ops = edit_script(old, new);
+    let changed: Vec<usize> = (0..ops.len()).filter(|k| ops[*k].0 != ' ').collect();
+    let mut out = Vec::new();
+    let mut k = 0;
+    while k < changed.len() {
+        // Extend the hunk while the next change is close enough to share context
+        let mut last = k;
+        while last + 1 < changed.len() && changed[last + 1] - changed[last] <= 2 * context + 1 {
+            last += 1;
+        }
+        let start = changed[k].saturating_sub(context);
+        let end = (changed[last] + context + 1).min(ops.len());
+        let (_, i, j) = ops[start];
+        let old_count = ops[start..end].iter().filter(|op| op.0 != '+').count();
+        let new_count = ops[start..end].iter().filter(|op| op.0 != '-').count();
+        out.push((Paint::Range, format!("@@ -{} +{} @@", unified_range(i, old_count), unified_range(j, new_count))));
+        for &(op, i, j) in &ops[start..end] {
+            match op {
+                '-' => push_line(&mut out, Paint::Removed, "-", &old[i]),
+                '+' => push_line(&mut out, Paint::Added, "+", &new[j]),
+                _ => push_line(&mut out, Paint::Plain, " ", &old[i]),
+            }
+        }
+        k = last + 1;
+    }
+    out
+}
+
+/// What a line of diff output shows, for coloring
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Paint {
+    Plain,
+    Header,
+    Range,
+    Removed,
+    Added,
+}
+
+impl Paint {
+    /// ANSI escape that starts this kind of line
+    fn code(self) -> &'static str {
+        match self {
+            Paint::Plain => "",
+            Paint::Header => "\x1b[1m",
+            Paint::Range => "\x1b[36m",
+            Paint::Removed => "\x1b[31m",
+            Paint::Added => "\x1b[32m",
+        }
+    }
+}
+
+/// Apply one commit's renames, new files, diffs and deletions to the previous state
+fn apply_commit(state: &mut HashMap<String, Vec<String>>, commit: &CommitEntry, idx: usize) -> Result<(), String> {
+    let mut moved = Vec::new();
+    for (from, to) in &commit.renamed {
+        let lines = state
+            .remove(from)
+            .ok_or_else(|| format!("commit #{} renames {} which does not exist", idx, from))?;
+        moved.push((to.clone(), lines));
+    }
+    state.extend(moved);
+    for (path, blob) in &commit.init {
+        let lines = read_blob(blob).map_err(|e| format!("commit #{} {}: {}", idx, path, e))?;
+        state.insert(path.clone(), lines);
+    }
+    for (path, diff) in &commit.diff {
+        let old = state
+            .get(path)
+            .ok_or_else(|| format!("commit #{} diffs {} which does not exist yet", idx, path))?;
+        let new = apply_file_diff(old, diff, false).map_err(|e| format!("commit #{} {}: {}", idx, path, e))?;
+        state.insert(path.clone(), new);
+    }
+    for path in &commit.deleted {
+        state.remove(path);
+    }
+    Ok(())
+}
+
+/// Undo one commit, turning its state back into the previous one
+fn undo_commit(state: &mut HashMap<String, Vec<String>>, commit: &CommitEntry, idx: usize) -> Result<(), String> {
+    // Deletions only record the path, so the old content has to come from replaying forward
+    if !commit.deleted.is_empty() {
+        return Err(format!("commit #{} deletes files", idx));
+    }
+    for (path, diff) in &commit.diff {
+        let new = state
+            .get(path)
+            .ok_or_else(|| format!("commit #{} diffs {} which is not tracked", idx, path))?;
+        let old = apply_file_diff(new, diff, true).map_err(|e| format!("commit #{} {}: {}", idx, path, e))?;
+        state.insert(path.clone(), old);
+    }
+    for path in commit.init.keys() {
+        state.remove(path);
+    }
+    let mut moved = Vec::new();
+    for (from, to) in &commit.renamed {
+        let lines = state
+            .remove(to)
+            .ok_or_else(|| format!("commit #{} renames to {} which is not tracked", idx, to))?;
+        moved.push((from.clone(), lines));
+    }
+    state.extend(moved);
+    Ok(())
+}
+
+/// Commits from the root down to `index`, following first parents
+fn first_parent_chain(data: &ScmData, index: usize) -> Vec<usize> {
+    let mut chain = vec![index];
+    let mut current = index;
+    while let Some(parent) = first_parent(data, current) {
+        chain.push(parent);
+        current = parent;
+    }
+    chain.reverse();
+    chain
+}
+
+/// Rebuild the files of commit `index` by replaying every commit from the root
+fn replay_forward(data: &ScmData, index: usize) -> Result<HashMap<String, Vec<String>>, String> {
+    let mut state: HashMap<String, Vec<String>> = HashMap::new();
+    for idx in first_parent_chain(data, index) {
+        let commit = full_commit(data, idx).map_err(|e| e.to_string())?;
+        apply_commit(&mut state, &commit, idx)?;
+    }
+    Ok(state)
+}
+
+/// Rebuild the files of commit `index` by undoing commits from the checked-out one in `latest`
+fn replay_backward(data: &ScmData, index: usize) -> Result<HashMap<String, Vec<String>>, String> {
+    let chain = first_parent_chain(data, head_index(data));
+    let start = chain
+        .iter()
+        .position(|idx| *idx == index)
+        .ok_or_else(|| format!("commit #{} is not an ancestor of the checked-out commit", index))?;
+    let mut state = data.latest.clone();
+    for idx in chain[start + 1..].iter().rev() {
+        let commit = full_commit(data, *idx).map_err(|e| e.to_string())?;
+        undo_commit(&mut state, &commit, *idx)?;
+    }
+    Ok(state)
+}
+
+/// Rebuild the exact files of commit `index` from its tree, or by replaying diffs along its
+/// first-parent history
+fn reconstruct(data: &ScmData, index: usize) -> Result<HashMap<String, Vec<String>>, ScmError> {
+    if index >= data.commits.len() {
+        return Err(ScmError::MissingCommit(format!("No commit #{}", index)));
+    }
+    // Every commit stores a snapshot; replaying diffs is only needed if it has gone missing
+    if let Ok(state) = read_tree(&data.commits[index].tree) {
+        return Ok(state);
+    }
+    // Undoing from `latest` is cheap near the checked-out commit, but only trustworthy if it
+    // lands on the recorded hash (older .scm files could put already-tracked files in `init`)
+    if let Ok(state) = replay_backward(data, index) {
+        if full_commit(data, index).is_ok_and(|commit| compute_hash(&commit, &state) == commit.hash) {
+            return Ok(state);
+        }
+    }
+    replay_forward(data, index).map_err(|e| ScmError::CorruptHistory(format!("cannot rebuild commit #{}: {}", index, e)))
+}
+
+/// Carry line origins through a diff's hunks: kept lines keep theirs, added ones come from `idx`
+fn blame_hunks(origins: &[usize], hunks: &[Hunk], idx: usize) -> Result<Vec<usize>, String> {
+    let mut result = Vec::with_capacity(origins.len());
+    let mut cursor = 0;
+    for hunk in hunks {
+        let (start, end, added) = match hunk {
+            Hunk::Delete { old, lines, .. } => (old.saturating_sub(1), old.saturating_sub(1) + lines.len(), 0),
+            Hunk::Add { old, lines, .. } => (*old, *old, lines.len()),
+        };
+        if start < cursor || end > origins.len() {
+            return Err(format!("hunk at old line {} is out of order or out of range", start));
+        }
+        result.extend_from_slice(&origins[cursor..start]);
+        result.extend(std::iter::repeat_n(idx, added));
+        cursor = end;
+    }
+    result.extend_from_slice(&origins[cursor..]);
+    Ok(result)
+}
+
+/// Index of the commit that introduced each line of every file in commit `index`, found by
+/// walking its first-parent history forward through the hunks. Lines a merge takes unchanged
+/// from another parent keep their origin on that side; `merged` caches those parents' origins
+fn line_origins(
+    data: &ScmData,
+    index: usize,
+    merged: &mut HashMap<usize, HashMap<String, Vec<usize>>>,
+) -> Result<HashMap<String, Vec<usize>>, ScmError> {
+    let mut origins: HashMap<String, Vec<usize>> = HashMap::new();
+    for idx in first_parent_chain(data, index) {
+        let commit = &data.commits[idx];
+        let corrupt = |e: String| ScmError::CorruptHistory(format!("commit #{} {}", idx, e));
+        let mut moved = Vec::new();
+        for (from, to) in &commit.renamed {
+            let lines = origins
+                .remove(from)
+                .ok_or_else(|| corrupt(format!("renames {} which does not exist", from)))?;
+            moved.push((to.clone(), lines));
+        }
+        origins.extend(moved);
+        for (path, blob) in &commit.init {
+            origins.insert(path.clone(), vec![idx; read_blob(blob)?.len()]);
+        }
+        for (path, diff) in &commit.diff {
+            let old = origins
+                .get(path)
+                .ok_or_else(|| corrupt(format!("diffs {} which does not exist yet", path)))?;
+            let new = match blob_change(diff) {
+                Some((_, blob)) => vec![idx; read_blob(blob)?.len()],
+                None => parse_diff(diff)
+                    .and_then(|hunks| blame_hunks(old, &hunks, idx))
+                    .map_err(|e| corrupt(format!("{}: {}", path, e)))?,
+            };
+            origins.insert(path.clone(), new);
+        }
+        for path in &commit.deleted {
+            origins.remove(path);
+        }
+        
+        let others: Vec<usize> = parent_indices(data, idx).into_iter().skip(1).collect();
+        if others.is_empty() {
+            continue;
+        }
+        let files = reconstruct(data, idx)?;
+        for other in others {
+            if !merged.contains_key(&other) {
+                let theirs = line_origins(data, other, merged)?;
+                merged.insert(other, theirs);
+            }
+            let other_files = reconstruct(data, other)?;
+            for path in commit.init.keys().chain(commit.diff.keys()) {
+                let (Some(old), Some(new), Some(theirs), Some(ours)) =
+                    (other_files.get(path), files.get(path), merged[&other].get(path), origins.get_mut(path))
+                else {
+                    continue;
+                };
+                for (op, i, j) in edit_script(old, new) {
+                    if op == ' ' && ours.get(j) == Some(&idx) {
+                        ours[j] = theirs[i];
+                    }
+                }
+            }
+        }
+    }
+    Ok(origins)
+}
+
+/// Build Merkle tree from commit hashes
+fn build_merkle_tree(hashes: &[String]) -> Vec<Vec<String>> {
+    if hashes.is_empty() {
+        return vec![];}
+    let leaves: Vec<String> = hashes.iter().map(|hash| merkle_leaf(hash)).collect();
+    let mut tree = vec![leaves.clone()];
+    let mut current_level = leaves;
+    while current_level.len() > 1 {
+        let mut next_level = Vec::new();
+        for chunk in current_level.chunks(2) {
+            next_level.push(merkle_parent(&chunk[0], chunk.get(1).map(String::as_str)));
+        }
+        tree.push(next_level.clone());
+        current_level = next_level;
+    }
+    tree
+}
+
+/// Root of a Merkle tree from `build_merkle_tree`, or `""` for an empty history
+fn merkle_root(levels: &[Vec<String>]) -> String {
+    levels.last().and_then(|level| level.first()).cloned().unwrap_or_default()
+}
+
+/// Merkle leaf of a commit hash. Leaves start with a 0 byte and inner nodes with a 1, so no
+/// inner node can be passed off as a commit
+fn merkle_leaf(hash: &str) -> String {
+    let mut hasher = Sha512::new();
+    hasher.update([0u8]);
+    hasher.update(hash.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Hash of a Merkle node from its children; an odd node out is hashed on its own
+fn merkle_parent(left: &str, right: Option<&str>) -> String {
+    let mut hasher = Sha512::new();
+    hasher.update([1u8]);
+    hasher.update(left.as_bytes());
+    if let Some(right) = right {
+        hasher.update(right.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// One level of a Merkle inclusion proof, naming the side the sibling hash goes on
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "side", rename_all = "lowercase")]
+enum ProofStep {
+    Left { sibling: String },
+    Right { sibling: String },
+    /// The node had no sibling and was hashed on its own
+    Alone,
+}
+
+/// Evidence that a commit hash is a leaf of the Merkle tree with the given root
+#[derive(Serialize, Deserialize, Debug)]
+struct MerkleProof {
+    commit: String,
+    index: usize,
+    /// Number of commits in the tree, which fixes the shape of the path
+    commits: usize,
+    root: String,
+    path: Vec<ProofStep>,
+}
+
+/// Sibling hashes from leaf `index` up to the root of `levels`
+fn merkle_proof(levels: &[Vec<String>], index: usize) -> Vec<ProofStep> {
+    let mut path = Vec::new();
+    let mut pos = index;
+    for level in &levels[..levels.len().saturating_sub(1)] {
+        path.push(if pos % 2 == 1 {
+            ProofStep::Left { sibling: level[pos - 1].clone() }
+        } else if pos + 1 < level.len() {
+            ProofStep::Right { sibling: level[pos + 1].clone() }
+        } else {
+            ProofStep::Alone
+        });
+        pos /= 2;
+    }
+    path
+}
+
+/// Leaf a proof path leads up from, read off the sides its siblings are on
+fn proof_index(path: &[ProofStep]) -> Option<usize> {
+    path.iter().enumerate().try_fold(0usize, |index, (level, step)| match step {
+        ProofStep::Left { .. } => Some(index | 1usize.checked_shl(u32::try_from(level).ok()?)?),
+        _ => Some(index),
+    })
+}
+
+/// Whether a proof path has the shape of the one `merkle_proof` gives for leaf `index` of a
+/// tree with `leaves` leaves
+fn proof_fits(path: &[ProofStep], index: usize, leaves: usize) -> bool {
+    if index >= leaves {
+        return false;
+    }
+    let (mut pos, mut width) = (index, leaves);
+    for step in path {
+        let fits = match step {
+            ProofStep::Left { .. } => pos % 2 == 1,
+            ProofStep::Right { .. } => pos % 2 == 0 && pos + 1 < width,
+            ProofStep::Alone => pos % 2 == 0 && pos + 1 == width,
+        };
+        if width <= 1 || !fits {
+            return false;
+        }
+        pos /= 2;
+        width = width.div_ceil(2);
+    }
+    width == 1
+}
+
+/// Root reached by hashing the leaf of commit `hash` with each step of a proof
+fn merkle_root_from(hash: &str, path: &[ProofStep]) -> String {
+    path.iter().fold(merkle_leaf(hash), |node, step| match step {
+        ProofStep::Left { sibling } => merkle_parent(sibling, Some(&node)),
+        ProofStep::Right { sibling } => merkle_parent(&node, Some(sibling)),
+        ProofStep::Alone => merkle_parent(&node, None),
+    })
+}
+
+/// Fraction of lines two versions have in common, from the length of their LCS
+fn similarity(a: &[String], b: &[String]) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 0.0;
+    }
+    let common = build_lcs_table(a, b)[0][0];
+    2.0 * common as f64 / (a.len() + b.len()) as f64
+}
+
+/// Pair files that disappeared with similar files that appeared, best matches first
+fn detect_renames(
+    gone: &[&String],
+    added: &[&String],
+    old: &HashMap<String, Vec<String>>,
+    new: &HashMap<String, Vec<String>>,
+) -> HashMap<String, String> {
+    let mut candidates = Vec::new();
+    for from in gone {
+        for to in added {
+            let score = similarity(&old[*from], &new[*to]);
+            if score >= RENAME_THRESHOLD {
+                candidates.push((score, (*from).clone(), (*to).clone()));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| (&a.1, &a.2).cmp(&(&b.1, &b.2))));
+    
+    let mut renamed = HashMap::new();
+    let mut taken = Vec::new();
+    for (_, from, to) in candidates {
+        if !renamed.contains_key(&from) && !taken.contains(&to) {
+            taken.push(to.clone());
+            renamed.insert(from, to);
+        }
+    }
+    renamed
+}
+
+/// Describe the change from `old` to `new` as a commit entry on top of `parents`; the hash is
+/// filled in by `stamp_commit`
+fn record_changes(
+    parents: Vec<String>,
+    old: &HashMap<String, Vec<String>>,
+    new: &HashMap<String, Vec<String>>,
+) -> CommitEntry {
+    let mut gone: Vec<&String> = old.keys().filter(|path| !new.contains_key(*path)).collect();
+    let mut added: Vec<&String> = new.keys().filter(|path| !old.contains_key(*path)).collect();
+    gone.sort();
+    added.sort();
+    let renamed = detect_renames(&gone, &added, old, new);
+    
+    // Separate new files from modified files
+    let mut init: HashMap<String, String> = HashMap::new();
+    let mut diff: HashMap<String, Vec<String>> = HashMap::new();
+    for (path, lines) in new {
+        let old_path = renamed.iter().find(|(_, to)| *to == path).map(|(from, _)| from).unwrap_or(path);
+        match old.get(old_path) {
+            // New file
+            None => {
+                init.insert(path.clone(), blob_id(lines));
+            }
+            // Check if modified
+            // Binary files change whole, and both versions are already stored as blobs
+            Some(old_lines) if old_lines != lines && (is_binary(old_lines) || is_binary(lines)) => {
+                diff.insert(path.clone(), vec![format!("{}{} {}", BLOB_CHANGE, blob_id(old_lines), blob_id(lines))]);
+            }
+            Some(old_lines) if old_lines != lines => {
+                let file_diff = generate_diff(old_lines, lines);
+                if !file_diff.is_empty() {
+                    diff.insert(path.clone(), file_diff);
+                }
+            }
+            Some(_) => {}
+        }
+    }
+    let deleted = gone.into_iter().filter(|path| !renamed.contains_key(*path)).cloned().collect();
+    
+    CommitEntry {
+        hash: String::new(),
+        parents,
+        tree: String::new(),
+        staged: String::new(),
+        init,
+        diff,
+        deleted,
+        renamed,
+        message: String::new(),
+        author: String::new(),
+        timestamp: None,
+        signature: None,
+    }
+}
+
+/// Whether a commit entry records any change at all
+fn has_changes(commit: &CommitEntry) -> bool {
+    !commit.init.is_empty() || !commit.diff.is_empty() || !commit.deleted.is_empty() || !commit.renamed.is_empty()
+}
+
+/// Newest commit both `a` and `b` descend from
+fn merge_base(scm_data: &ScmData, a: usize, b: usize) -> Option<usize> {
+    let theirs = ancestors(scm_data, b);
+    // Ancestors always come before their descendants, so the last shared one is the nearest
+    ancestors(scm_data, a).into_iter().filter(|idx| theirs.contains(idx)).max()
+}
+
+/// Range of base lines `start..end` and the lines that replace it
+type Region = (usize, usize, Vec<String>);
+
+/// Ranges of `base` replaced by `other`
+fn change_regions(base: &[String], other: &[String]) -> Vec<Region> {
+    // generate_diff output always parses, since it is produced right here
+    let hunks = parse_diff(&generate_diff(base, other)).unwrap_or_default();
+    let mut regions: Vec<Region> = Vec::new();
+    for hunk in hunks {
+        let (start, end, lines) = match hunk {
+            Hunk::Delete { old, lines, .. } => (old - 1, old - 1 + lines.len(), Vec::new()),
+            Hunk::Add { old, lines, .. } => (old, old, lines),
+        };
+        match regions.last_mut() {
+            Some(last) if last.1 == start => {
+                last.1 = end;
+                last.2.extend(lines);
+            }
+            _ => regions.push((start, end, lines)),
+        }
+    }
+    regions
+}
+
+/// Apply the regions of one side that fall inside `base[start..end]`
+fn apply_regions(base: &[String], start: usize, end: usize, regions: &[&Region]) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut cursor = start;
+    for (from, to, lines) in regions {
+        result.extend_from_slice(&base[cursor..*from]);
+        result.extend(lines.iter().cloned());
+        cursor = *to;
+    }
+    result.extend_from_slice(&base[cursor..end]);
+    result
+}
+
+/// Line-based three-way merge; overlapping or touching edits become conflict markers
+fn merge_lines(base: &[String], ours: &[String], theirs: &[String], labels: (&str, &str)) -> (Vec<String>, bool) {
+    let our_regions = change_regions(base, ours);
+    let their_regions = change_regions(base, theirs);
+    let mut all: Vec<(bool, &Region)> = our_regions
+        .iter()
+        .map(|region| (true, region))
+        .chain(their_regions.iter().map(|region| (false, region)))
+        .collect();
+    all.sort_by_key(|(is_ours, region)| (region.0, region.1, !*is_ours));
+    
+    let mut result = Vec::new();
+    let mut conflict = false;
+    let mut cursor = 0;
+    let mut i = 0;
+    while i < all.len() {
+        // Gather every region that overlaps or touches the cluster so far
+        let start = all[i].1 .0;
+        let mut end = all[i].1 .1;
+        let mut j = i + 1;
+        while j < all.len() && all[j].1 .0 <= end {
+            end = end.max(all[j].1 .1);
+            j += 1;
+        }
+        let cluster = &all[i..j];
+        let mine: Vec<_> = cluster.iter().filter(|(is_ours, _)| *is_ours).map(|(_, r)| *r).collect();
+        let other: Vec<_> = cluster.iter().filter(|(is_ours, _)| !*is_ours).map(|(_, r)| *r).collect();
+        
+        result.extend_from_slice(&base[cursor..start]);
+        let our_text = apply_regions(base, start, end, &mine);
+        let their_text = apply_regions(base, start, end, &other);
+        if other.is_empty() || our_text == their_text {
+            result.extend(our_text);
+        } else if mine.is_empty() {
+            result.extend(their_text);
+        } else {
+            conflict = true;
+            result.push(format!("<<<<<<< {}", labels.0));
+            result.extend(our_text);
+            result.push("=======".to_string());
+            result.extend(their_text);
+            result.push(format!(">>>>>>> {}", labels.1));
+        }
+        cursor = end;
+        i = j;
+    }
+    result.extend_from_slice(&base[cursor..]);
+    (result, conflict)
+}
+
+/// Three-way merge of one path; `None` means the file should not exist
+fn merge_file(
+    base: Option<&Vec<String>>,
+    ours: Option<&Vec<String>>,
+    theirs: Option<&Vec<String>>,
+    labels: (&str, &str),
+) -> (Option<Vec<String>>, bool) {
+    if ours == theirs || base == theirs {
+        return (ours.cloned(), false);
+    }
+    if base == ours {
+        return (theirs.cloned(), false);
+    }
+    match (ours, theirs) {
+        // Binary files cannot be merged line by line; keep ours for the user to replace
+        (Some(ours), Some(theirs)) if is_binary(ours) || is_binary(theirs) => (Some(ours.clone()), true),
+        (Some(ours), Some(theirs)) => {
+            let (lines, conflict) = merge_lines(base.map(Vec::as_slice).unwrap_or(&[]), ours, theirs, labels);
+            (Some(lines), conflict)
+        }
+        // Modified on one side and deleted on the other: keep the edit for the user to decide
+        (Some(kept), None) | (None, Some(kept)) => (Some(kept.clone()), true),
+        (None, None) => (None, false),
+    }
+}
+
+/// Repository settings kept in `.scm/config`
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Config {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    author: Option<String>,
+    /// File holding the key new commits are signed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signing_key: Option<String>,
+}
+
+fn load_config() -> Result<Config, ScmError> {
+    match fs::read(scm_path("config")) {
+        Ok(bytes) => parse_json(&bytes, ".scm/config"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(ScmError::Io(format!(".scm/config: {}", e))),
+    }
+}
+
+/// Author of new commits: `$SCM_AUTHOR`, then the repository config, then `$USER`
+fn commit_author() -> Result<String, ScmError> {
+    if let Some(name) = env::var("SCM_AUTHOR").ok().filter(|name| !name.is_empty()) {
+        return Ok(name);
+    }
+    let configured = if Path::new(SCM_DIR).is_dir() { load_config()?.author } else { None };
+    Ok(configured
+        .or_else(|| env::var("USER").ok())
+        .unwrap_or_else(|| "unknown".to_string()))
+}
+
+/// Seconds since the Unix epoch, or the date in `$SCM_DATE` when set
+fn commit_time() -> Result<u64, ScmError> {
+    if let Ok(date) = env::var("SCM_DATE") {
+        return parse_date(&date, false).ok_or_else(|| ScmError::Usage(format!("Invalid SCM_DATE: {}", date)));
+    }
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0))
+}
+
+/// Message given with `-m`, else one written in `$EDITOR` starting from `default`, else `default`
+fn commit_message(args: &[String], default: &str) -> Result<String, ScmError> {
+    if let Some(pos) = args.iter().position(|arg| arg == "-m" || arg == "--message") {
+        return match args.get(pos + 1) {
+            Some(message) => Ok(message.trim_end().to_string()),
+            None => Err(ScmError::Usage(format!("Option {} needs a message", args[pos]))),
+        };
+    }
+    let editor = match env::var("EDITOR") {
+        Ok(editor) if !editor.trim().is_empty() => editor,
+        _ => return Ok(default.to_string()),
+    };
+    let path = env::temp_dir().join(format!("scm-commit-{}.txt", process::id()));
+    let template = format!("{}\n# Enter the commit message. Lines starting with '#' are ignored.\n", default);
+    fs::write(&path, template).map_err(io_error(path.display()))?;
+    // Run through the shell so EDITOR may carry its own arguments
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&path)
+        .status();
+    let content = fs::read_to_string(&path);
+    fs::remove_file(&path).ok();
+    if !status.map(|s| s.success()).unwrap_or(false) {
+        return Err(ScmError::Refused(format!("Editor '{}' failed; aborting commit", editor)));
+    }
+    let content = content.map_err(io_error(path.display()))?;
+    let message: Vec<&str> = content.lines().filter(|line| !line.starts_with('#')).collect();
+    let message = message.join("\n").trim().to_string();
+    if message.is_empty() {
+        return Err(ScmError::Refused("Aborting commit due to empty message".to_string()));
+    }
+    Ok(message)
+}
+
+/// Set the message, author and time of a new commit and hash it together with its files
+fn stamp_commit(commit: &mut CommitEntry, message: String, files: &HashMap<String, Vec<String>>) -> Result<(), ScmError> {
+    commit.message = message;
+    commit.author = commit_author()?;
+    commit.timestamp = Some(commit_time()?);
+    commit.hash = compute_hash(commit, files);
+    if let Some(path) = signing_key_path()? {
+        let key = load_signing_key(&path)?;
+        commit.signature = Some(CommitSignature {
+            key: hex::encode(key.verifying_key().to_bytes()),
+            sig: hex::encode(key.sign(commit.hash.as_bytes()).to_bytes()),
+        });
+    }
+    Ok(())
+}
+
+/// Key file new commits are signed with: `$SCM_SIGNING_KEY`, then the repository config
+fn signing_key_path() -> Result<Option<String>, ScmError> {
+    if let Some(path) = env::var("SCM_SIGNING_KEY").ok().filter(|path| !path.is_empty()) {
+        return Ok(Some(path));
+    }
+    Ok(if Path::new(SCM_DIR).is_dir() { load_config()?.signing_key } else { None })
+}
+
+/// Read a secret key written by `scm keygen`
+fn load_signing_key(path: &str) -> Result<SigningKey, ScmError> {
+    let text = fs::read_to_string(path).map_err(io_error(path))?;
+    hex::decode(text.trim())
+        .ok()
+        .and_then(|bytes| <[u8; SECRET_KEY_LENGTH]>::try_from(bytes).ok())
+        .map(|secret| SigningKey::from_bytes(&secret))
+        .ok_or_else(|| ScmError::Parse(format!("{}: not a signing key from 'scm keygen'", path)))
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Date of a day count from `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (if month <= 2 { yoe + era * 400 + 1 } else { yoe + era * 400 }, month, day)
+}
+
+/// Format a Unix timestamp as `YYYY-MM-DD HH:MM:SS UTC`
+fn format_timestamp(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+/// Parse `@<seconds>`, `YYYY-MM-DD` or `YYYY-MM-DD HH:MM[:SS]` (UTC); a bare date means the
+/// start of the day, or its last second when `end_of_day` is set
+fn parse_date(text: &str, end_of_day: bool) -> Option<u64> {
+    let text = text.trim();
+    if let Some(secs) = text.strip_prefix('@') {
+        return secs.parse().ok();
+    }
+    let (date, time) = match text.split_once([' ', 'T']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (text, None),
+    };
+    let parts: Vec<i64> = date.split('-').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let [year, month, day] = parts[..] else { return None };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let seconds = match time {
+        Some(time) => {
+            let parts: Vec<i64> = time.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+            match parts[..] {
+                [h, m] if h < 24 && m < 60 => h * 3600 + m * 60,
+                [h, m, s] if h < 24 && m < 60 && s < 60 => h * 3600 + m * 60 + s,
+                _ => return None,
+            }
+        }
+        None if end_of_day => 86399,
+        None => 0,
+    };
+    u64::try_from(days_from_civil(year, month, day) * 86400 + seconds).ok()
+}
+
+fn commit(args: &[String]) -> Result<(), ScmError> {
+    let current_files = read_working_tree()?;
+    
+    // An empty single-file .scm counts as uninitialized
+    if fs::metadata(SCM_DIR).map(|meta| meta.is_file() && meta.len() == 0).unwrap_or(false) {
+        fs::remove_file(SCM_DIR).ok();
+    }
+    // Check if .scm exists
+    if !Path::new(SCM_DIR).exists() {
+        if current_files.is_empty() {
+            return Err(ScmError::Refused("No files to commit".to_string()));
+        }
+        let message = commit_message(args, "")?;
+        // Initialize - first commit
+        say("Initializing SCM...");
+        
+        fs::create_dir_all(scm_path("objects")).map_err(io_error("cannot create .scm"))?;
+        let _lock = lock_repo()?;
+        let mut commit = record_changes(Vec::new(), &HashMap::new(), &current_files);
+        stamp_commit(&mut commit, message, &current_files)?;
+        commit.tree = write_tree(&current_files)?;
+        
+        let mut scm_data = ScmData {
+            latest: current_files,
+            commits: Vec::new(),
+            merkle: Vec::new(),
+            head: None,
+            branch: DEFAULT_BRANCH.to_string(),
+            branches: BTreeMap::new(),
+            merging: None,
+            merge_added: Vec::new(),
+            conflicts: Vec::new(),
+            ids: Vec::new(),
+            index: None,
+            tags: BTreeMap::new(),
+            listed: None,
+            history_loaded: true,
+        };
+        scm_data.branches.insert(DEFAULT_BRANCH.to_string(), commit.hash.clone());
+        push_commit(&mut scm_data, commit)?;
+        save_scm(&scm_data)?;
+        report(&json!({ "index": 0, "hash": scm_data.commits[0].hash, "branch": scm_data.branch }));
+        say(format!("Initialized with {} files", scm_data.latest.len()));
+    } else {
+        // Load existing SCM data
+        let _lock = lock_repo()?;
+        let mut scm_data = load_scm()?;
+        if let Some(head) = scm_data.head {
+            return Err(ScmError::Refused(format!(
+                "HEAD is detached at commit #{}; switch to a branch before committing",
+                head
+            )));
+        }
+        // `-a` stages every change first, new files included
+        let staged = if args.iter().any(|arg| arg == "-a" || arg == "--all") {
+            current_files
+        } else {
+            staged_files(&scm_data)?
+        };
+        let mut parents = vec![scm_data.commits[head_index(&scm_data)].hash.clone()];
+        let mut default_message = String::new();
+        if let Some(theirs) = scm_data.merging.take() {
+            // Binary and modify/delete conflicts leave no markers, so each conflict must be staged
+            if !scm_data.conflicts.is_empty() {
+                let mut msg = "Stage each conflicted file with 'scm add' to mark it resolved before committing the merge:"
+                    .to_string();
+                for path in &scm_data.conflicts {
+                    msg.push_str(&format!("\n  {}", display_path(path)));
+                }
+                return Err(ScmError::Refused(msg));
+            }
+            let unresolved: Vec<&String> = staged
+                .iter()
+                .filter(|(_, lines)| lines.iter().any(|line| line.starts_with("<<<<<<< ")))
+                .map(|(path, _)| path)
+                .collect();
+            if !unresolved.is_empty() {
+                let mut msg = "Resolve conflict markers and stage the files before committing the merge:".to_string();
+                for path in unresolved {
+                    msg.push_str(&format!("\n  {}", path));
+                }
+                return Err(ScmError::Refused(msg));
+            }
+            scm_data.merge_added.clear();
+            default_message = format!("Merge commit {}", short(&theirs));
+            parents.push(theirs);
+        }
+        let mut commit = record_changes(parents, &scm_data.latest, &staged);
+        // A merge is worth recording even when it leaves our files unchanged
+        if !has_changes(&commit) && commit.parents.len() < 2 {
+            say("No changes staged for commit (use 'scm add <paths>' or 'scm commit -a')");
+            return Ok(());
+        }
+        stamp_commit(&mut commit, commit_message(args, &default_message)?, &staged)?;
+        let hash = commit.hash.clone();
+        
+        if index_of(&scm_data, &hash).is_none() {
+            // Only blobs that changed are new objects; the rest are already stored
+            commit.tree = write_tree(&staged)?;
+            push_commit(&mut scm_data, commit)?;
+        }
+        scm_data.branches.insert(scm_data.branch.clone(), hash.clone());
+        scm_data.latest = staged;
+        scm_data.index = None;
+        save_scm(&scm_data)?;
+        
+        report(&json!({ "index": head_index(&scm_data), "hash": hash, "branch": scm_data.branch }));
+        say(format!("Committed changes (hash: {}...)", short(&hash)));
+    }
+    Ok(())
+}
+
+/// Files that the next commit will record: the staged files, or `latest` if nothing is staged
+fn staged_files(scm_data: &ScmData) -> Result<HashMap<String, Vec<String>>, ScmError> {
+    let Some(index) = &scm_data.index else {
+        return Ok(scm_data.latest.clone());
+    };
+    let mut files = HashMap::new();
+    for (path, id) in index {
+        let lines = read_blob(id)
+            .map_err(|e| ScmError::CorruptHistory(format!("Cannot read staged {}: {}", display_path(path), e)))?;
+        files.insert(path.clone(), lines);
+    }
+    Ok(files)
+}
+
+/// Stage exactly `files` for the next commit
+fn set_staged(scm_data: &mut ScmData, files: &HashMap<String, Vec<String>>) -> Result<(), ScmError> {
+    scm_data.index = if *files == scm_data.latest {
+        None
+    } else {
+        let mut index = BTreeMap::new();
+        for (path, lines) in files {
+            index.insert(path.clone(), write_blob(lines)?);
+        }
+        Some(index)
+    };
+    Ok(())
+}
+
+/// Lines folded by `display_lines` as stored again
+fn unfold_lines(mut lines: Vec<String>) -> Vec<String> {
+    if let Some(last) = lines.last_mut() {
+        if let Some(text) = last.strip_suffix(NO_NEWLINE).map(String::from) {
+            *last = text;
+            lines.push(NO_NEWLINE.to_string());
+        }
+    }
+    lines
+}
+
+/// Ask a yes/no question about staging on the terminal; `None` means quit
+fn ask(question: &str, options: &str) -> Option<char> {
+    loop {
+        print!("{} [{}]? ", question, options);
+        io::stdout().flush().ok();
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).unwrap_or(0) == 0 {
+            println!();
+            return None;
+        }
+        match answer.trim().chars().next() {
+            Some('q') => return None,
+            Some(c) if options.contains(c) && c != ',' => return Some(c),
+            _ => println!("Answer one of {}", options),
+        }
+    }
+}
+
+/// Let the user pick which changes between `staged` and `working` to stage, hunk by hunk;
+/// returns the lines to stage and whether to stop asking about further files
+fn select_hunks(path: &str, staged: &[String], working: &[String], color: bool) -> (Vec<String>, bool) {
+    let old = display_lines(staged);
+    let new = display_lines(working);
+    let blocks = change_blocks(&edit_script(&old, &new));
+    let mut result = Vec::new();
+    let mut cursor = 0;
+    let mut rest: Option<bool> = None;
+    let mut quit = false;
+    print_diff(&[(Paint::Header, format!("diff a/{} b/{}", path, path))], color);
+    for (n, &(i0, i1, j0, j1)) in blocks.iter().enumerate() {
+        let take = match rest {
+            Some(take) => take,
+            None => {
+                // Context stops at the neighbouring changes, whose lines differ between the sides
+                let before = i0.saturating_sub(3).max(if n > 0 { blocks[n - 1].1 } else { 0 });
+                let after = (i1 + 3).min(blocks.get(n + 1).map_or(old.len(), |next| next.0));
+                let mut out = vec![(
+                    Paint::Range,
+                    format!(
+                        "@@ -{} +{} @@",
+                        unified_range(before, after - before),
+                        unified_range(j0 - (i0 - before), j1 - j0 + after - before - (i1 - i0))
+                    ),
+                )];
+                for line in &old[before..i0] {
+                    push_line(&mut out, Paint::Plain, " ", line);
+                }
+                for line in &old[i0..i1] {
+                    push_line(&mut out, Paint::Removed, "-", line);
+                }
+                for line in &new[j0..j1] {
+                    push_line(&mut out, Paint::Added, "+", line);
+                }
+                for line in &old[i1..after] {
+                    push_line(&mut out, Paint::Plain, " ", line);
+                }
+                print_diff(&out, color);
+                match ask(&format!("Stage this hunk ({}/{})", n + 1, blocks.len()), "y,n,a,d,q") {
+                    Some('y') => true,
+                    Some('a') => *rest.insert(true),
+                    Some('d') => *rest.insert(false),
+                    None => {
+                        quit = true;
+                        *rest.insert(false)
+                    }
+                    _ => false,
+                }
+            }
+        };
+        result.extend_from_slice(&old[cursor..i0]);
+        result.extend_from_slice(if take { &new[j0..j1] } else { &old[i0..i1] });
+        cursor = i1;
+    }
+    result.extend_from_slice(&old[cursor..]);
+    (unfold_lines(result), quit)
+}
+
+/// Stage the working-tree state of `paths`, or pick changes hunk by hunk with `-p`
+fn add(args: &[String]) -> Result<(), ScmError> {
+    require_repo()?;
+    let patch = args.iter().any(|arg| arg == "-p" || arg == "--patch");
+    let paths: Vec<String> = args.iter().filter(|arg| !arg.starts_with('-')).cloned().collect();
+    if paths.is_empty() && !patch {
+        return Err(ScmError::Usage("Usage: scm add [-p] <paths>...".to_string()));
+    }
+    if patch && json_output() {
+        return Err(ScmError::Usage("add -p asks questions, so it cannot be used with --json".to_string()));
+    }
+    let filters = if paths.is_empty() { vec![String::new()] } else { normalize_paths(&paths) };
+    let _lock = lock_repo()?;
+    let mut scm_data = load_scm()?;
+    let mut staged = staged_files(&scm_data)?;
+    let working = read_working_tree()?;
+    
+    let mut candidates: Vec<&String> = staged.keys().chain(working.keys()).collect();
+    candidates.sort();
+    candidates.dedup();
+    candidates.retain(|path| path_selected(path, &filters));
+    for filter in &filters {
+        if !candidates.iter().any(|path| path_selected(path, std::slice::from_ref(filter))) {
+            return Err(ScmError::Usage(format!("Path '{}' did not match any files", filter)));
+        }
+    }
+    
+    let color = io::stdout().is_terminal();
+    for path in candidates.into_iter().cloned().collect::<Vec<_>>() {
+        let (before, after) = (staged.get(&path), working.get(&path));
+        if before == after {
+            continue;
+        }
+        let name = display_path(&path);
+        let lines = match (before, after) {
+            (Some(before), Some(after)) if patch && !is_binary(before) && !is_binary(after) => {
+                let (lines, quit) = select_hunks(name, before, after, color);
+                staged.insert(path.clone(), lines);
+                if quit {
+                    break;
+                }
+                continue;
+            }
+            _ if patch => {
+                let question = match after {
+                    None => format!("Stage deletion of {}", name),
+                    Some(_) if before.is_none() => format!("Stage new file {}", name),
+                    Some(_) => format!("Stage binary change to {}", name),
+                };
+                match ask(&question, "y,n,q") {
+                    Some('y') => after.cloned(),
+                    Some(_) => continue,
+                    None => break,
+                }
+            }
+            _ => after.cloned(),
+        };
+        match lines {
+            Some(lines) => staged.insert(path, lines),
+            None => staged.remove(&path),
+        };
+    }
+    set_staged(&mut scm_data, &staged)?;
+    // Naming a conflicted file marks it resolved, even if it is staged as the merge left it
+    if !patch {
+        scm_data.conflicts.retain(|path| !path_selected(path, &filters));
+    }
+    report_staged(&scm_data.latest, &staged);
+    save_scm(&scm_data)
+}
+
+/// Report what the next commit will record with `--json`, as `status` codes and paths
+fn report_staged(latest: &HashMap<String, Vec<String>>, staged: &HashMap<String, Vec<String>>) {
+    let staged: Vec<_> = file_changes(latest, staged)
+        .into_iter()
+        .map(|(code, path)| json!({ "staged": code, "path": path }))
+        .collect();
+    report(&json!({ "staged": staged }));
+}
+
+/// Unstage `paths`, or everything, returning them to the checked-out commit's content
+fn reset(args: &[String]) -> Result<(), ScmError> {
+    require_repo()?;
+    let _lock = lock_repo()?;
+    let mut scm_data = load_scm()?;
+    let filters = if args.is_empty() { vec![String::new()] } else { normalize_paths(args) };
+    let mut staged = staged_files(&scm_data)?;
+    staged.retain(|path, _| !path_selected(path, &filters));
+    for (path, lines) in &scm_data.latest {
+        if path_selected(path, &filters) {
+            staged.insert(path.clone(), lines.clone());
+        }
+    }
+    set_staged(&mut scm_data, &staged)?;
+    report_staged(&scm_data.latest, &staged);
+    save_scm(&scm_data)
+}
+
+fn revert() -> Result<(), ScmError> {
+    require_repo()?;
+    
+    let _lock = lock_repo()?;
+    let mut scm_data = load_scm()?;
+    if let Some(head) = scm_data.head {
+        return Err(ScmError::Refused(format!(
+            "HEAD is detached at commit #{}; switch to a branch before reverting",
+            head
+        )));
+    }
+    
+    let tip = head_index(&scm_data);
+    let previous = first_parent(&scm_data, tip)
+        .ok_or_else(|| ScmError::Refused("No previous commit to revert to".to_string()))?;
+    say(format!("Reverting commit {}...", short(&scm_data.commits[tip].hash)));
+    
+    // The reverted commit stays in history; only the branch moves back
+    check_out_tree(&mut scm_data, previous, true)?;
+    let hash = scm_data.commits[previous].hash.clone();
+    scm_data.branches.insert(scm_data.branch.clone(), hash);
+    save_scm(&scm_data)?;
+    report(&json!({ "index": previous, "hash": scm_data.commits[previous].hash, "branch": scm_data.branch }));
+    say("Reverted to previous commit");
+    Ok(())
+}
+
+/// Whether tracked files or the staging area differ from the checked-out commit; untracked
+/// files are not uncommitted changes until something would overwrite them
+fn has_local_changes(scm_data: &ScmData, working: &HashMap<String, Vec<String>>) -> bool {
+    scm_data.index.is_some() || scm_data.latest.iter().any(|(path, lines)| working.get(path) != Some(lines))
+}
+
+/// Refuse to write `target` over untracked files it would give other contents
+fn check_untracked(
+    scm_data: &ScmData,
+    working: &HashMap<String, Vec<String>>,
+    target: &HashMap<String, Vec<String>>,
+) -> Result<(), ScmError> {
+    let mut clobbered: Vec<&str> = working
+        .iter()
+        .filter(|(path, lines)| !scm_data.latest.contains_key(*path) && target.get(*path).is_some_and(|new| new != *lines))
+        .map(|(path, _)| display_path(path))
+        .collect();
+    if clobbered.is_empty() {
+        return Ok(());
+    }
+    clobbered.sort();
+    let mut msg = "Untracked files would be overwritten; move or remove them first:".to_string();
+    for path in clobbered {
+        msg.push_str(&format!("\n  {}", path));
+    }
+    Err(ScmError::DirtyTree(msg))
+}
+
+/// Replace the working tree with commit `index`, refusing to clobber uncommitted changes
+fn check_out_tree(scm_data: &mut ScmData, index: usize, force: bool) -> Result<(), ScmError> {
+    let current_files = read_working_tree()?;
+    if has_local_changes(scm_data, &current_files) && !force {
+        return Err(ScmError::DirtyTree(
+            "Working tree has uncommitted changes; commit them or use --force".to_string(),
+        ));
+    }
+    
+    let target = reconstruct(scm_data, index)?;
+    if !force {
+        check_untracked(scm_data, &current_files, &target)?;
+    }
+    
+    for path in scm_data.latest.keys() {
+        if !target.contains_key(path) {
+            remove_file(path);
+        }
+    }
+    write_files(&target)?;
+    scm_data.latest = target;
+    // Replacing the tree abandons any merge in progress and anything staged
+    scm_data.merging = None;
+    scm_data.merge_added.clear();
+    scm_data.conflicts.clear();
+    scm_data.index = None;
+    Ok(())
+}
+
+/// Find the commit named by a branch, tag, `#idx` or a unique hash prefix
+fn resolve_commit(scm_data: &ScmData, spec: &str) -> Result<usize, ScmError> {
+    if let Some(idx) = branch_index(scm_data, spec) {
+        return Ok(idx);
+    }
+    if let Some(idx) = scm_data.tags.get(spec).and_then(|tag| index_of(scm_data, &tag.commit)) {
+        return Ok(idx);
+    }
+    if let Some(idx) = spec.strip_prefix('#') {
+        return match idx.parse::<usize>() {
+            Ok(idx) if idx < scm_data.commits.len() => Ok(idx),
+            _ => Err(ScmError::MissingCommit(format!("No commit {}", spec))),
+        };
+    }
+    let matches: Vec<usize> = scm_data
+        .commits
+        .iter()
+        .enumerate()
+        .filter(|(_, commit)| !spec.is_empty() && commit.hash.starts_with(spec))
+        .map(|(idx, _)| idx)
+        .collect();
+    match matches.len() {
+        0 => Err(ScmError::MissingCommit(format!("No commit matches '{}'", spec))),
+        1 => Ok(matches[0]),
+        _ => {
+            let mut msg = format!("Hash prefix '{}' is ambiguous; candidates:", spec);
+            for idx in matches {
+                msg.push_str(&format!("\n  #{} {}", idx, scm_data.commits[idx].hash));
+            }
+            Err(ScmError::MissingCommit(msg))
+        }
+    }
+}
+
+fn checkout(args: &[String]) -> Result<(), ScmError> {
+    let force = args.iter().any(|arg| arg == "--force" || arg == "-f");
+    let spec = args
+        .iter()
+        .find(|arg| !arg.starts_with('-'))
+        .ok_or_else(|| ScmError::Usage("Usage: scm checkout <hash-prefix|#idx> [--force]".to_string()))?;
+    require_repo()?;
+    
+    let _lock = lock_repo()?;
+    let mut scm_data = load_scm()?;
+    if scm_data.branches.contains_key(spec) {
+        return switch_branch(&mut scm_data, spec, force);
+    }
+    let index = resolve_commit(&scm_data, spec)?;
+    
+    check_out_tree(&mut scm_data, index, force)?;
+    scm_data.head = if branch_index(&scm_data, &scm_data.branch) == Some(index) { None } else { Some(index) };
+    save_scm(&scm_data)?;
+    report(&json!({ "index": index, "hash": scm_data.commits[index].hash, "detached": scm_data.head.is_some() }));
+    say(format!("Checked out commit #{} ({}...)", index, short(&scm_data.commits[index].hash)));
+    Ok(())
+}
+
+/// Branch and tag names must be usable wherever a commit reference is accepted
+fn valid_branch_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with(['-', '#']) && !name.contains(char::is_whitespace)
+}
+
+fn branch(args: &[String]) -> Result<(), ScmError> {
+    require_repo()?;
+    // Listing branches changes nothing, so it needs no lock
+    let _lock = if args.is_empty() { None } else { Some(lock_repo()?) };
+    let mut scm_data = load_scm()?;
+    
+    match args {
+        [] if json_output() => {
+            let branches: Vec<_> = scm_data
+                .branches
+                .iter()
+                .map(|(name, hash)| json!({ "name": name, "index": index_of(&scm_data, hash), "hash": hash }))
+                .collect();
+            let current = if scm_data.head.is_none() { Some(&scm_data.branch) } else { None };
+            report(&json!({ "branches": branches, "current": current, "detached": scm_data.head }));
+        }
+        [] => {
+            if let Some(head) = scm_data.head {
+                println!("* (detached at #{})", head);
+            }
+            for (name, hash) in &scm_data.branches {
+                let marker = if *name == scm_data.branch && scm_data.head.is_none() { "*" } else { " " };
+                let idx = index_of(&scm_data, hash).map(|idx| format!("#{}", idx)).unwrap_or_default();
+                println!("{} {} {} {}...", marker, name, idx, short(hash));
+            }
+        }
+        [flag, name] if flag == "-d" => {
+            if *name == scm_data.branch {
+                return Err(ScmError::Refused(format!("Cannot delete the current branch '{}'", name)));
+            }
+            if scm_data.branches.remove(name).is_none() {
+                return Err(ScmError::MissingCommit(format!("No branch named '{}'", name)));
+            }
+            save_scm(&scm_data)?;
+            say(format!("Deleted branch {}", name));
+        }
+        [name] | [name, _] => {
+            if !valid_branch_name(name) {
+                return Err(ScmError::Usage(format!("Invalid branch name '{}'", name)));
+            }
+            if scm_data.branches.contains_key(name) {
+                return Err(ScmError::Refused(format!("Branch '{}' already exists", name)));
+            }
+            if scm_data.tags.contains_key(name) {
+                return Err(ScmError::Refused(format!("'{}' is already a tag", name)));
+            }
+            let index = match args.get(1) {
+                Some(spec) => resolve_commit(&scm_data, spec)?,
+                None => head_index(&scm_data),
+            };
+            let hash = scm_data.commits[index].hash.clone();
+            scm_data.branches.insert(name.clone(), hash);
+            save_scm(&scm_data)?;
+            report(&json!({ "name": name, "index": index, "hash": scm_data.commits[index].hash }));
+            say(format!("Created branch {} at commit #{}", name, index));
+        }
+        _ => return Err(ScmError::Usage("Usage: scm branch [-d] [<name> [<commit>]]".to_string())),
+    }
+    Ok(())
+}
+
+/// Check out a branch's tip and attach HEAD to it
+fn switch_branch(scm_data: &mut ScmData, name: &str, force: bool) -> Result<(), ScmError> {
+    let index = branch_index(scm_data, name)
+        .ok_or_else(|| ScmError::MissingCommit(format!("No branch named '{}'", name)))?;
+    check_out_tree(scm_data, index, force)?;
+    scm_data.branch = name.to_string();
+    scm_data.head = None;
+    save_scm(scm_data)?;
+    report(&json!({ "index": index, "hash": scm_data.commits[index].hash, "branch": name }));
+    say(format!("Switched to branch {} (commit #{})", name, index));
+    Ok(())
+}
+
+fn switch(args: &[String]) -> Result<(), ScmError> {
+    let force = args.iter().any(|arg| arg == "--force" || arg == "-f");
+    let name = args
+        .iter()
+        .find(|arg| !arg.starts_with('-'))
+        .ok_or_else(|| ScmError::Usage("Usage: scm switch <branch> [--force]".to_string()))?;
+    require_repo()?;
+    let _lock = lock_repo()?;
+    let mut scm_data = load_scm()?;
+    switch_branch(&mut scm_data, name, force)
+}
+
+/// Stash entries' commit object ids, newest first
+fn read_stash() -> Result<Vec<String>, ScmError> {
+    match fs::read_to_string(scm_path("stash")) {
+        Ok(list) => Ok(list.lines().map(String::from).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(ScmError::Io(format!(".scm/stash: {}", e))),
+    }
+}
+
+fn write_stash(ids: &[String]) -> Result<(), ScmError> {
+    let list: String = ids.iter().map(|id| format!("{}\n", id)).collect();
+    write_atomic(&scm_path("stash"), list.as_bytes())
+}
+
+/// Position in the stash named by `n` or `stash@{n}`, the newest entry when not given
+fn stash_position(arg: Option<&String>, len: usize) -> Result<usize, ScmError> {
+    if len == 0 {
+        return Err(ScmError::Refused("No stash entries".to_string()));
+    }
+    let Some(arg) = arg else {
+        return Ok(0);
+    };
+    let number = arg.strip_prefix("stash@{").and_then(|rest| rest.strip_suffix('}')).unwrap_or(arg);
+    match number.parse() {
+        Ok(n) if n < len => Ok(n),
+        _ => Err(ScmError::MissingCommit(format!("No stash entry {}", arg))),
+    }
+}
+
+/// Put uncommitted changes aside as a commit-like record of their diffs against the checked-out
+/// commit, with the staged files kept as a tree of their own, then restore that commit's files; `pop` merges them back
+fn stash(args: &[String]) -> Result<(), ScmError> {
+    require_repo()?;
+    let usage = || ScmError::Usage("Usage: scm stash [-m <message>] | stash list | stash pop [<n>] | stash drop [<n>]".to_string());
+    match args.first().map(String::as_str) {
+        Some("list") => {
+            let ids = read_stash()?;
+            let mut out = Vec::new();
+            let mut entries = Vec::new();
+            for (n, id) in ids.iter().enumerate() {
+                let entry = read_commit(id)?;
+                out.push(format!("stash@{{{}}}: {}", n, entry.message));
+                entries.push(json!({ "name": format!("stash@{{{}}}", n), "commit": entry }));
+            }
+            if json_output() {
+                report(&json!({ "entries": entries }));
+            } else {
+                print_lines(&out);
+            }
+            Ok(())
+        }
+        Some("drop") => {
+            // The list is read under the lock, or a concurrent stash could be written over
+            let _lock = lock_repo()?;
+            let mut ids = read_stash()?;
+            let n = stash_position(args.get(1), ids.len())?;
+            let id = ids.remove(n);
+            write_stash(&ids)?;
+            // A damaged entry can still be dropped
+            let hash = read_commit(&id).ok().map(|entry| entry.hash);
+            report(&json!({ "name": format!("stash@{{{}}}", n), "hash": hash }));
+            say(format!("Dropped stash@{{{}}} ({}...)", n, short(&id)));
+            Ok(())
+        }
+        Some("pop") => {
+            let _lock = lock_repo()?;
+            let mut ids = read_stash()?;
+            let n = stash_position(args.get(1), ids.len())?;
+            let mut scm_data = load_scm()?;
+            pop_stash(&mut scm_data, &ids[n], n)?;
+            save_scm(&scm_data)?;
+            ids.remove(n);
+            write_stash(&ids)?;
+            Ok(())
+        }
+        None | Some("push" | "-m" | "--message") => {
+            let _lock = lock_repo()?;
+            let mut ids = read_stash()?;
+            let mut scm_data = load_scm()?;
+            if scm_data.merging.is_some() {
+                return Err(ScmError::Refused(
+                    "A merge is in progress; commit it or run 'scm merge --abort' before stashing".to_string(),
+                ));
+            }
+            let working = read_working_tree()?;
+            if working == scm_data.latest && scm_data.index.is_none() {
+                say("No local changes to save");
+                return Ok(());
+            }
+            let head = head_index(&scm_data);
+            let base = &scm_data.commits[head];
+            let on = match scm_data.head {
+                Some(head) => format!("#{}", head),
+                None => scm_data.branch.clone(),
+            };
+            // Unlike commits, a stash without -m never opens an editor
+            let message = match args.iter().position(|arg| arg == "-m" || arg == "--message") {
+                Some(pos) => match args.get(pos + 1) {
+                    Some(message) => format!("On {}: {}", on, message),
+                    None => return Err(ScmError::Usage(format!("Option {} needs a message", args[pos]))),
+                },
+                None => {
+                    let summary = full_commit(&scm_data, head)?.message.lines().next().unwrap_or_default().to_string();
+                    format!("WIP on {}: #{} {}", on, head, summary)
+                }
+            };
+            // Untracked files are stashed too, so the restored tree is exactly the commit's
+            let mut entry = record_changes(vec![base.hash.clone()], &scm_data.latest, &working);
+            entry.message = message.trim_end().to_string();
+            entry.author = commit_author()?;
+            entry.timestamp = Some(commit_time()?);
+            entry.hash = compute_hash(&entry, &working);
+            entry.tree = write_tree(&working)?;
+            if scm_data.index.is_some() {
+                entry.staged = write_tree(&staged_files(&scm_data)?)?;
+            }
+            ids.insert(0, write_commit(&entry)?);
+            write_stash(&ids)?;
+            
+            for path in working.keys() {
+                if !scm_data.latest.contains_key(path) {
+                    remove_file(path);
+                }
+            }
+            check_out_tree(&mut scm_data, head, true)?;
+            save_scm(&scm_data)?;
+            report(&json!({ "name": "stash@{0}", "hash": entry.hash, "message": entry.message }));
+            say(format!("Saved working tree changes as stash@{{0}}: {}", entry.message));
+            Ok(())
+        }
+        _ => Err(usage()),
+    }
+}
+
+/// Merge the changes of stash entry `id` into a clean working tree, from the commit they were
+/// made on to the checked-out one, and stage what was staged when it merges cleanly too
+fn pop_stash(scm_data: &mut ScmData, id: &str, n: usize) -> Result<(), ScmError> {
+    let working = read_working_tree()?;
+    if has_local_changes(scm_data, &working) {
+        return Err(ScmError::DirtyTree(
+            "Working tree has uncommitted changes; commit or stash them before popping".to_string(),
+        ));
+    }
+    let entry = read_commit(id)?;
+    let base = entry
+        .parents
+        .first()
+        .and_then(|hash| index_of(scm_data, hash))
+        .ok_or_else(|| ScmError::MissingCommit(format!("stash@{{{}}} was made on a commit that is not in history", n)))?;
+    let base_files = reconstruct(scm_data, base)?;
+    let mut stashed = base_files.clone();
+    apply_commit(&mut stashed, &entry, base).map_err(|e| ScmError::CorruptHistory(format!("stash@{{{}}}: {}", n, e)))?;
+    if compute_hash(&entry, &stashed) != entry.hash {
+        return Err(ScmError::CorruptHistory(format!("stash@{{{}}}: changes do not match its hash", n)));
+    }
+    
+    let staged = match entry.staged.as_str() {
+        "" => None,
+        tree => Some(
+            read_tree(tree).map_err(|e| ScmError::CorruptHistory(format!("stash@{{{}}}: staged files: {}", n, e)))?,
+        ),
+    };
+    
+    let ours = &scm_data.latest;
+    let (restored, conflicts) = merge_stashed(&base_files, ours, &stashed);
+    check_untracked(scm_data, &working, &restored)?;
+    let restaged = match &staged {
+        Some(staged) => match merge_stashed(&base_files, ours, staged) {
+            (files, clashes) if clashes.is_empty() => Some(files),
+            _ => None,
+        },
+        None => None,
+    };
+    for path in ours.keys() {
+        if !restored.contains_key(path) {
+            remove_file(path);
+        }
+    }
+    write_files(&restored)?;
+    report(&json!({ "name": format!("stash@{{{}}}", n), "hash": entry.hash, "conflicts": conflicts }));
+    if !conflicts.is_empty() {
+        let mut msg = format!("Conflicts restoring stash@{{{}}}; it is kept until you fix them and run 'scm stash drop':", n);
+        for path in conflicts {
+            msg.push_str(&format!("\n  {}", path));
+        }
+        return Err(ScmError::Refused(msg));
+    }
+    say(format!("Restored stash@{{{}}}: {}", n, entry.message));
+    match restaged {
+        Some(files) => set_staged(scm_data, &files)?,
+        None if staged.is_some() => say("Staged changes no longer apply cleanly and were left unstaged"),
+        None => {}
+    }
+    Ok(())
+}
+
+/// Three-way merge of every file from the commit a stash was made on, the checked-out files and
+/// the stashed ones, with the paths that conflicted
+fn merge_stashed(
+    base: &HashMap<String, Vec<String>>,
+    ours: &HashMap<String, Vec<String>>,
+    stashed: &HashMap<String, Vec<String>>,
+) -> (HashMap<String, Vec<String>>, Vec<String>) {
+    let mut paths: Vec<&String> = base.keys().chain(ours.keys()).chain(stashed.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    let mut restored = HashMap::new();
+    let mut conflicts = Vec::new();
+    for path in paths {
+        let (lines, conflict) =
+            merge_file(base.get(path), ours.get(path), stashed.get(path), ("Updated upstream", "Stashed changes"));
+        if conflict {
+            conflicts.push(display_path(path).to_string());
+        }
+        if let Some(lines) = lines {
+            restored.insert(path.clone(), lines);
+        }
+    }
+    (restored, conflicts)
+}
+
+/// List tags, tag a commit (annotated when given a message) or delete a tag
+fn tag(args: &[String]) -> Result<(), ScmError> {
+    require_repo()?;
+    let message_at = args.iter().position(|arg| arg == "-m" || arg == "--message");
+    let words: Vec<&String> = args
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| message_at.is_none_or(|at| *i != at && *i != at + 1))
+        .map(|(_, arg)| arg)
+        .collect();
+    // Listing tags changes nothing, so it needs no lock
+    let _lock = if args.is_empty() { None } else { Some(lock_repo()?) };
+    let mut scm_data = load_scm()?;
+    
+    match words.as_slice() {
+        [] if message_at.is_none() && json_output() => {
+            let tags: Vec<_> = scm_data
+                .tags
+                .iter()
+                .map(|(name, tag)| json!({ "name": name, "index": index_of(&scm_data, &tag.commit), "tag": tag }))
+                .collect();
+            report(&json!({ "tags": tags }));
+        }
+        [] if message_at.is_none() => {
+            for (name, tag) in &scm_data.tags {
+                let idx = index_of(&scm_data, &tag.commit).map(|idx| format!("#{}", idx)).unwrap_or_default();
+                match tag.message.lines().next() {
+                    Some(summary) => println!("{} {} {}... {}", name, idx, short(&tag.commit), summary),
+                    None => println!("{} {} {}...", name, idx, short(&tag.commit)),
+                }
+            }
+        }
+        [flag, name] if *flag == "-d" && message_at.is_none() => {
+            if scm_data.tags.remove(*name).is_none() {
+                return Err(ScmError::MissingCommit(format!("No tag named '{}'", name)));
+            }
+            save_scm(&scm_data)?;
+            say(format!("Deleted tag {}", name));
+        }
+        [name] | [name, _] if !name.starts_with('-') => {
+            if !valid_branch_name(name) {
+                return Err(ScmError::Usage(format!("Invalid tag name '{}'", name)));
+            }
+            if scm_data.tags.contains_key(*name) {
+                return Err(ScmError::Refused(format!("Tag '{}' already exists", name)));
+            }
+            if scm_data.branches.contains_key(*name) {
+                return Err(ScmError::Refused(format!("'{}' is already a branch", name)));
+            }
+            let index = match words.get(1) {
+                Some(spec) => resolve_commit(&scm_data, spec)?,
+                None => head_index(&scm_data),
+            };
+            let mut tag = Tag {
+                commit: scm_data.commits[index].hash.clone(),
+                message: String::new(),
+                tagger: String::new(),
+                timestamp: None,
+            };
+            if message_at.is_some() {
+                tag.message = commit_message(args, "")?;
+                tag.tagger = commit_author()?;
+                tag.timestamp = Some(commit_time()?);
+            }
+            report(&json!({ "name": name, "index": index, "tag": tag }));
+            scm_data.tags.insert(name.to_string(), tag);
+            save_scm(&scm_data)?;
+            say(format!("Tagged commit #{} as {}", index, name));
+        }
+        _ => return Err(ScmError::Usage("Usage: scm tag [-d <name> | <name> [<commit>] [-m <message>]]".to_string())),
+    }
+    Ok(())
+}
+
+fn merge(args: &[String]) -> Result<(), ScmError> {
+    require_repo()?;
+    let _lock = lock_repo()?;
+    let mut scm_data = load_scm()?;
+    let head = head_index(&scm_data);
+    
+    if args.iter().any(|arg| arg == "--abort") {
+        if scm_data.merging.is_none() {
+            return Err(ScmError::Refused("No merge in progress".to_string()));
+        }
+        // Files only the other side had are not in `latest`, so checking it out would leave them
+        for path in &scm_data.merge_added {
+            if !scm_data.latest.contains_key(path) {
+                remove_file(path);
+            }
+        }
+        check_out_tree(&mut scm_data, head, true)?;
+        save_scm(&scm_data)?;
+        report(&json!({ "index": head, "hash": scm_data.commits[head].hash, "branch": scm_data.branch }));
+        say(format!("Merge aborted; restored commit #{}", head));
+        return Ok(());
+    }
+    let message_at = args.iter().position(|arg| arg == "-m" || arg == "--message").map(|pos| pos + 1);
+    let spec = match args.iter().enumerate().find(|(i, arg)| Some(*i) != message_at && !arg.starts_with('-')) {
+        Some((_, spec)) => spec,
+        None => return Err(ScmError::Usage("Usage: scm merge <branch> [-m <message>] | --abort".to_string())),
+    };
+    if let Some(head) = scm_data.head {
+        return Err(ScmError::Refused(format!(
+            "HEAD is detached at commit #{}; switch to a branch before merging",
+            head
+        )));
+    }
+    if scm_data.merging.is_some() {
+        return Err(ScmError::Refused(
+            "A merge is already in progress; commit it or run 'scm merge --abort'".to_string(),
+        ));
+    }
+    let working = read_working_tree()?;
+    if has_local_changes(&scm_data, &working) {
+        return Err(ScmError::DirtyTree(
+            "Working tree has uncommitted changes; commit them before merging".to_string(),
+        ));
+    }
+    let other = resolve_commit(&scm_data, spec)?;
+    
+    let base = merge_base(&scm_data, head, other);
+    if base == Some(other) {
+        report(&json!({ "index": head, "hash": scm_data.commits[head].hash, "branch": scm_data.branch }));
+        say("Already up to date");
+        return Ok(());
+    }
+    let branch = scm_data.branch.clone();
+    if base == Some(head) {
+        check_out_tree(&mut scm_data, other, false)?;
+        let hash = scm_data.commits[other].hash.clone();
+        scm_data.branches.insert(branch.clone(), hash);
+        save_scm(&scm_data)?;
+        report(&json!({ "index": other, "hash": scm_data.commits[other].hash, "branch": branch, "fast_forward": true }));
+        say(format!("Fast-forwarded {} to commit #{}", branch, other));
+        return Ok(());
+    }
+    
+    let base_files = match base {
+        Some(base) => reconstruct(&scm_data, base)?,
+        None => HashMap::new(),
+    };
+    let their_files = reconstruct(&scm_data, other)?;
+    let our_files = scm_data.latest.clone();
+    
+    let mut paths: Vec<&String> = base_files.keys().chain(our_files.keys()).chain(their_files.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    let mut merged: HashMap<String, Vec<String>> = HashMap::new();
+    let mut conflicts = Vec::new();
+    for path in paths {
+        let (lines, conflict) = merge_file(
+            base_files.get(path),
+            our_files.get(path),
+            their_files.get(path),
+            (&branch, spec),
+        );
+        if conflict {
+            conflicts.push(path.clone());
+        }
+        if let Some(lines) = lines {
+            merged.insert(path.clone(), lines);
+        }
+    }
+    
+    check_untracked(&scm_data, &working, &merged)?;
+    for path in our_files.keys() {
+        if !merged.contains_key(path) {
+            remove_file(path);
+        }
+    }
+    write_files(&merged)?;
+    let their_hash = scm_data.commits[other].hash.clone();
+    
+    if !conflicts.is_empty() {
+        report(&json!({ "conflicts": conflicts.iter().map(|path| display_path(path)).collect::<Vec<_>>() }));
+        // Keep `latest` at our commit so the merge can be aborted or committed later; the merged
+        // files are staged, conflict markers included, until the user stages a resolution
+        scm_data.merging = Some(their_hash);
+        scm_data.merge_added =
+            merged.keys().filter(|path| !our_files.contains_key(*path) && !working.contains_key(*path)).cloned().collect();
+        scm_data.merge_added.sort();
+        scm_data.conflicts = conflicts.clone();
+        set_staged(&mut scm_data, &merged)?;
+        save_scm(&scm_data)?;
+        let mut msg = "Automatic merge failed; fix conflicts and run 'scm commit':".to_string();
+        for path in conflicts {
+            msg.push_str(&format!("\n  {}", path));
+        }
+        return Err(ScmError::Refused(msg));
+    }
+    
+    let our_hash = scm_data.commits[head].hash.clone();
+    let mut commit = record_changes(vec![our_hash, their_hash], &our_files, &merged);
+    let message = commit_message(args, &format!("Merge {} into {}", spec, branch))?;
+    stamp_commit(&mut commit, message, &merged)?;
+    let hash = commit.hash.clone();
+    if index_of(&scm_data, &hash).is_none() {
+        commit.tree = write_tree(&merged)?;
+        push_commit(&mut scm_data, commit)?;
+    }
+    scm_data.branches.insert(branch.clone(), hash.clone());
+    scm_data.latest = merged;
+    save_scm(&scm_data)?;
+    report(&json!({ "index": head_index(&scm_data), "hash": hash, "branch": branch, "fast_forward": false }));
+    say(format!("Merged {} into {} (hash: {}...)", spec, branch, short(&hash)));
+    Ok(())
+}
+
+fn config(args: &[String]) -> Result<(), ScmError> {
+    require_repo()?;
+    let mut config = load_config()?;
+    let usage = || ScmError::Usage("Usage: scm config (author | signing-key) [<value>]".to_string());
+    match args {
+        [] if json_output() => report(&config),
+        [] => {
+            if let Some(author) = &config.author {
+                println!("author = {}", author);
+            }
+            if let Some(path) = &config.signing_key {
+                println!("signing-key = {}", path);
+            }
+        }
+        [key] => {
+            let value = match key.as_str() {
+                "author" => &config.author,
+                "signing-key" => &config.signing_key,
+                _ => return Err(usage()),
+            };
+            match value {
+                Some(value) if !json_output() => println!("{}", value),
+                Some(value) => report(&json!({ "value": value })),
+                None => return Err(ScmError::Refused(format!("'{}' is not set", key))),
+            }
+        }
+        [key, value] => {
+            match key.as_str() {
+                "author" => config.author = Some(value.clone()),
+                "signing-key" => {
+                    // Fail now rather than on the next commit
+                    load_signing_key(value)?;
+                    config.signing_key = Some(value.clone());
+                }
+                _ => return Err(usage()),
+            }
+            let _lock = lock_repo()?;
+            let mut json = serde_json::to_vec_pretty(&config).unwrap_or_default();
+            json.push(b'\n');
+            write_atomic(&scm_path("config"), &json)?;
+            report(&config);
+        }
+        _ => return Err(usage()),
+    }
+    Ok(())
+}
+
+fn log(args: &[String]) -> Result<(), ScmError> {
+    require_repo()?;
+    
+    let mut all = false;
+    let mut oneline = false;
+    let mut stat = false;
+    let mut graph = false;
+    let mut template: Option<&str> = None;
+    let mut author: Option<&str> = None;
+    let mut since: Option<u64> = None;
+    let mut until: Option<u64> = None;
+    let mut filters: Vec<String> = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--all" => all = true,
+            "--oneline" => oneline = true,
+            "--stat" => stat = true,
+            "--graph" => graph = true,
+            "--" => filters.extend(rest.by_ref().cloned()),
+            "--author" | "--since" | "--until" | "--format" => {
+                let value = rest
+                    .next()
+                    .ok_or_else(|| ScmError::Usage(format!("Option {} needs a value", arg)))?;
+                match arg.as_str() {
+                    "--author" => author = Some(value),
+                    "--format" => template = Some(value),
+                    _ => {
+                        let secs = parse_date(value, arg == "--until").ok_or_else(|| {
+                            ScmError::Usage(format!(
+                                "Invalid date '{}'; use YYYY-MM-DD, 'YYYY-MM-DD HH:MM[:SS]' or @<seconds>",
+                                value
+                            ))
+                        })?;
+                        if arg == "--since" {
+                            since = Some(secs);
+                        } else {
+                            until = Some(secs);
+                        }
+                    }
+                }
+            }
+            _ if arg.starts_with("--format=") => template = Some(&arg["--format=".len()..]),
+            _ if arg.starts_with('-') => return Err(ScmError::Usage(format!("Unknown log option: {}", arg))),
+            _ => filters.push(arg.clone()),
+        }
+    }
+    let filters = normalize_paths(&filters);
+    
+    let mut scm_data = load_scm()?;
+    load_history(&mut scm_data)?;
+    let head = head_index(&scm_data);
+    let shown: Vec<usize> = if all {
+        (0..scm_data.commits.len()).collect()
+    } else {
+        ancestors(&scm_data, head)
+    };
+    // Commits made before timestamps existed have no date to match a range against
+    let shown: Vec<usize> = shown
+        .into_iter()
+        .filter(|idx| {
+            let commit = &scm_data.commits[*idx];
+            author.is_none_or(|name| commit.author.contains(name))
+                && since.is_none_or(|since| commit.timestamp.is_some_and(|t| t >= since))
+                && until.is_none_or(|until| commit.timestamp.is_some_and(|t| t <= until))
+                && (filters.is_empty() || touched_paths(commit).any(|path| path_selected(path, &filters)))
+        })
+        .collect();
+    if json_output() {
+        let mut commits = Vec::new();
+        for idx in shown.iter().rev().copied() {
+            let commit = &scm_data.commits[idx];
+            let branches: Vec<&String> =
+                scm_data.branches.iter().filter(|(_, tip)| **tip == commit.hash).map(|(name, _)| name).collect();
+            let tags: Vec<&String> =
+                scm_data.tags.iter().filter(|(_, tag)| tag.commit == commit.hash).map(|(name, _)| name).collect();
+            let mut entry = json!({
+                "index": idx,
+                "parents": parent_indices(&scm_data, idx),
+                "branches": branches,
+                "tags": tags,
+                "checked_out": idx == head,
+                "commit": commit,
+            });
+            if stat {
+                let files: Vec<_> = file_stats(&scm_data, idx)?
+                    .into_iter()
+                    .map(|(path, added, removed)| json!({ "path": path, "added": added, "removed": removed }))
+                    .collect();
+                entry["stat"] = json!(files);
+            }
+            commits.push(entry);
+        }
+        report(&json!({ "commits": commits, "merkle_root": merkle_root(&scm_data.merkle) }));
+        return Ok(());
+    }
+    let mut visible = vec![false; scm_data.commits.len()];
+    for idx in &shown {
+        visible[*idx] = true;
+    }
+    let full = !oneline && template.is_none();
+    let mut out = Vec::new();
+    if full {
+        out.extend(["Commit History:".to_string(), "==============".to_string()]);
+    }
+    let mut lanes = Graph::default();
+    // Parents always come before their children, so newest first is also child before parent
+    for idx in shown.iter().rev().copied() {
+        let commit = &scm_data.commits[idx];
+        let labels = commit_labels(&scm_data, idx, head);
+        let mut block = match template {
+            Some(template) => format_commit(template, &scm_data, idx, &labels).lines().map(String::from).collect(),
+            None if oneline => {
+                let labels = if labels.is_empty() { String::new() } else { format!(" ({})", labels.join(", ")) };
+                let summary = commit.message.lines().next().unwrap_or_default();
+                vec![format!("#{} {}{} {}", idx, short(&commit.hash), labels, summary).trim_end().to_string()]
+            }
+            None => commit_details(&scm_data, idx, &labels),
+        };
+        if block.is_empty() {
+            block.push(String::new());
+        }
+        if stat {
+            block.extend(commit_stat(&scm_data, idx)?);
+        }
+        if !graph {
+            if full {
+                out.push(String::new());
+            }
+            out.extend(block);
+            continue;
+        }
+        
+        // Only parents that are shown get a line to them
+        let parents: Vec<usize> = parent_indices(&scm_data, idx).into_iter().filter(|p| visible[*p]).collect();
+        if full {
+            out.push(lanes.prefix().trim_end().to_string());
+        }
+        out.extend(lanes.enter(idx));
+        out.push(format!("{}{}", lanes.commit_prefix(), block[0]));
+        out.extend(lanes.leave(&parents));
+        let prefix = lanes.prefix();
+        out.extend(block[1..].iter().map(|line| format!("{}{}", prefix, line).trim_end().to_string()));
+    }
+    if full {
+        if let Some(root) = scm_data.merkle.last().and_then(|level| level.first()) {
+            out.push(String::new());
+            out.push(format!("Merkle Root: {}", root));
+        }
+    }
+    print_lines(&out);
+    Ok(())
+}
+
+/// Branches and tags pointing at commit `idx`, and whether it is checked out
+fn commit_labels(scm_data: &ScmData, idx: usize, head: usize) -> Vec<String> {
+    let hash = &scm_data.commits[idx].hash;
+    let mut labels: Vec<String> =
+        scm_data.branches.iter().filter(|(_, tip)| *tip == hash).map(|(name, _)| name.clone()).collect();
+    labels.extend(
+        scm_data
+            .tags
+            .iter()
+            .filter(|(_, tag)| &tag.commit == hash)
+            .map(|(name, _)| format!("tag: {}", name)),
+    );
+    if idx == head {
+        labels.push("checked out".to_string());
+    }
+    labels
+}
+
+/// Default log entry for a commit
+fn commit_details(scm_data: &ScmData, idx: usize, labels: &[String]) -> Vec<String> {
+    let commit = &scm_data.commits[idx];
+    let mut out = Vec::new();
+    if labels.is_empty() {
+        out.push(format!("Commit #{}", idx));
+    } else {
+        out.push(format!("Commit #{} ({})", idx, labels.join(", ")));
+    }
+    out.push(format!("Hash: {}", commit.hash));
+    let parents: Vec<String> = parent_indices(scm_data, idx).iter().map(|p| format!("#{}", p)).collect();
+    match parents.len() {
+        0 => {}
+        1 => out.push(format!("Parent: {}", parents[0])),
+        _ => out.push(format!("Parents: {}", parents.join(", "))),
+    }
+    if !commit.author.is_empty() {
+        out.push(format!("Author: {}", commit.author));
+    }
+    if let Some(timestamp) = commit.timestamp {
+        out.push(format!("Date: {}", format_timestamp(timestamp)));
+    }
+    if let Some(signature) = &commit.signature {
+        out.push(format!("Signed-by: {}", short(&signature.key)));
+    }
+    if !commit.message.is_empty() {
+        out.push(String::new());
+        out.extend(commit.message.lines().map(|line| format!("    {}", line)));
+        out.push(String::new());
+    }
+    out.push(format!("New files: {}", commit.init.len()));
+    out.push(format!("Modified files: {}", commit.diff.len()));
+    if !commit.deleted.is_empty() {
+        out.push(format!("Deleted files: {}", commit.deleted.len()));
+    }
+    if !commit.renamed.is_empty() {
+        out.push(format!("Renamed files: {}", commit.renamed.len()));
+    }
+    out
+}
+
+/// Fill in a `log --format` template: %H hash, %h short hash, %i index, %p parent indices,
+/// %an author, %ad date, %at seconds since the epoch, %s subject, %b body, %d branches and
+/// tags, %n a new line and %% a percent sign; anything else is kept as written
+fn format_commit(template: &str, scm_data: &ScmData, idx: usize, labels: &[String]) -> String {
+    let commit = &scm_data.commits[idx];
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(pos) = rest.find('%') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        let (value, len) = match rest.get(..2).unwrap_or(rest) {
+            "an" => (commit.author.clone(), 2),
+            "ad" => (commit.timestamp.map(format_timestamp).unwrap_or_default(), 2),
+            "at" => (commit.timestamp.map(|t| t.to_string()).unwrap_or_default(), 2),
+            code => match code.chars().next() {
+                Some('H') => (commit.hash.clone(), 1),
+                Some('h') => (short(&commit.hash).to_string(), 1),
+                Some('i') => (idx.to_string(), 1),
+                Some('p') => {
+                    let parents: Vec<String> = parent_indices(scm_data, idx).iter().map(|p| format!("#{}", p)).collect();
+                    (parents.join(" "), 1)
+                }
+                Some('s') => (commit.message.lines().next().unwrap_or_default().to_string(), 1),
+                Some('b') => (commit.message.split_once('\n').map(|(_, body)| body.trim()).unwrap_or_default().to_string(), 1),
+                Some('d') if labels.is_empty() => (String::new(), 1),
+                Some('d') => (format!(" ({})", labels.join(", ")), 1),
+                Some('n') => ("\n".to_string(), 1),
+                Some('%') => ("%".to_string(), 1),
+                _ => ("%".to_string(), 0),
+            },
+        };
+        out.push_str(&value);
+        rest = &rest[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Every path a commit adds, changes, deletes or renames, under its old and new name
+fn touched_paths(commit: &CommitEntry) -> impl Iterator<Item = &String> {
+    commit
+        .init
+        .keys()
+        .chain(commit.diff.keys())
+        .chain(&commit.deleted)
+        .chain(commit.renamed.iter().flat_map(|(from, to)| [from, to]))
+}
+
+/// Lines added and removed per file by a commit, counted from its diffs; new files add all their
+/// lines and deleted ones remove all the lines they had in the first parent
+fn file_stats(scm_data: &ScmData, idx: usize) -> Result<Vec<(String, usize, usize)>, ScmError> {
+    let commit = &scm_data.commits[idx];
+    let text = |lines: &[String]| lines.iter().filter(|line| *line != NO_NEWLINE).count();
+    let diff_lines = |diff: &[String], prefix: &str| {
+        diff.iter().filter(|line| line.strip_prefix(prefix).is_some_and(|line| line != NO_NEWLINE)).count()
+    };
+    let name = |path: &String| match commit.renamed.iter().find(|(_, to)| *to == path) {
+        Some((from, _)) => format!("{} => {}", display_path(from), display_path(path)),
+        None => display_path(path).to_string(),
+    };
+    let mut files: Vec<(String, usize, usize)> = Vec::new();
+    for (path, blob) in &commit.init {
+        files.push((name(path), text(&read_blob(blob)?), 0));
+    }
+    for (path, diff) in &commit.diff {
+        files.push((name(path), diff_lines(diff, "> "), diff_lines(diff, "< ")));
+    }
+    for to in commit.renamed.values().filter(|to| !commit.diff.contains_key(*to)) {
+        files.push((name(to), 0, 0));
+    }
+    if !commit.deleted.is_empty() {
+        let parent = match first_parent(scm_data, idx) {
+            Some(parent) => reconstruct(scm_data, parent)?,
+            None => HashMap::new(),
+        };
+        for path in &commit.deleted {
+            files.push((name(path), 0, parent.get(path).map_or(0, |lines| text(lines))));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// `log --stat` lines for a commit
+fn commit_stat(scm_data: &ScmData, idx: usize) -> Result<Vec<String>, ScmError> {
+    let files = file_stats(scm_data, idx)?;
+    let width = files.iter().map(|(name, _, _)| name.chars().count()).max().unwrap_or(0);
+    let mut out: Vec<String> = files
+        .iter()
+        .map(|(name, added, removed)| format!(" {:<w$} | +{} -{}", name, added, removed, w = width))
+        .collect();
+    let added: usize = files.iter().map(|(_, added, _)| added).sum();
+    let removed: usize = files.iter().map(|(_, _, removed)| removed).sum();
+    out.push(format!(
+        " {} file{} changed, {} insertion{}(+), {} deletion{}(-)",
+        files.len(),
+        if files.len() == 1 { "" } else { "s" },
+        added,
+        if added == 1 { "" } else { "s" },
+        removed,
+        if removed == 1 { "" } else { "s" }
+    ));
+    Ok(out)
+}
+
+/// Columns of `log --graph`, each holding the commit its line is heading for
+#[derive(Default)]
+struct Graph {
+    lanes: Vec<Option<usize>>,
+    /// Column of the commit being drawn
+    column: usize,
+}
+
+impl Graph {
+    /// Lines continuing down every column
+    fn prefix(&self) -> String {
+        self.lanes.iter().map(|lane| if lane.is_some() { "| " } else { "  " }).collect()
+    }
+    
+    /// Take commit `idx` into its column, first joining every other line heading for it
+    fn enter(&mut self, idx: usize) -> Vec<String> {
+        self.column = match self.lanes.iter().position(|lane| *lane == Some(idx)) {
+            Some(column) => column,
+            None => self.free_column(),
+        };
+        self.lanes[self.column] = Some(idx);
+        let joining: Vec<usize> = (self.column + 1..self.lanes.len()).filter(|k| self.lanes[*k] == Some(idx)).collect();
+        if joining.is_empty() {
+            return Vec::new();
+        }
+        let mut row = self.prefix().into_bytes();
+        for k in joining {
+            row[2 * k] = b' ';
+            row[2 * k - 1] = b'/';
+            self.lanes[k] = None;
+        }
+        self.trim();
+        vec![String::from_utf8_lossy(&row).trim_end().to_string()]
+    }
+    
+    fn commit_prefix(&self) -> String {
+        let mut row = self.prefix().into_bytes();
+        row[2 * self.column] = b'*';
+        String::from_utf8_lossy(&row).to_string()
+    }
+    
+    /// Continue the commit's column to its first parent and branch off to the others in new
+    /// columns beside it, moving the columns right of it over
+    fn leave(&mut self, parents: &[usize]) -> Vec<String> {
+        self.lanes[self.column] = parents.first().copied();
+        let mut opened = 0;
+        for parent in parents.iter().skip(1) {
+            if !self.lanes.contains(&Some(*parent)) {
+                opened += 1;
+                self.lanes.insert(self.column + opened, Some(*parent));
+            }
+        }
+        let mut rows = Vec::new();
+        if opened > 0 {
+            let mut row = self.prefix().into_bytes();
+            for k in (self.column + 1..self.lanes.len()).filter(|k| self.lanes[*k].is_some()) {
+                row[2 * k] = b' ';
+                row[2 * k - 1] = b'\\';
+            }
+            rows.push(String::from_utf8_lossy(&row).trim_end().to_string());
+        }
+        self.trim();
+        rows
+    }
+    
+    /// Drop empty columns on the right
+    fn trim(&mut self) {
+        while self.lanes.last() == Some(&None) {
+            self.lanes.pop();
+        }
+    }
+    
+    /// First empty column, adding one if every column is in use
+    fn free_column(&mut self) -> usize {
+        match self.lanes.iter().position(Option::is_none) {
+            Some(column) => column,
+            None => {
+                self.lanes.push(None);
+                self.lanes.len() - 1
+            }
+        }
+    }
+}
+
+/// Files of the commit named by `spec`
+fn commit_files(scm_data: &ScmData, spec: &str) -> Result<HashMap<String, Vec<String>>, ScmError> {
+    reconstruct(scm_data, resolve_commit(scm_data, spec)?)
+}
+
+/// Paths given on the command line in the form `path_selected` compares against
+fn normalize_paths(paths: &[String]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| match display_path(path.trim_end_matches('/')) {
+            "." => String::new(),
+            path => path.to_string(),
+        })
+        .collect()
+}
+
+/// Whether a `diff` argument names a commit; bare hash prefixes must be `MIN_HASH_PREFIX` long,
+/// so short file names are not taken for commits
+fn diff_names_commit(scm_data: &ScmData, arg: &str) -> bool {
+    let named = arg.starts_with('#')
+        || arg.len() >= MIN_HASH_PREFIX
+        || branch_index(scm_data, arg).is_some()
+        || scm_data.tags.contains_key(arg);
+    named && resolve_commit(scm_data, arg).is_ok()
+}
+
+/// Whether a `diff` argument names a file or directory in the working tree, the checked-out
+/// commit or the staged files
+fn diff_names_path(scm_data: &ScmData, arg: &str) -> bool {
+    let filters = normalize_paths(&[arg.to_string()]);
+    Path::new(arg).exists()
+        || scm_data
+            .latest
+            .keys()
+            .chain(scm_data.index.iter().flat_map(|index| index.keys()))
+            .any(|path| path_selected(path, &filters))
+}
+
+/// Whether a path lies at or below one of the paths the user asked about; `""` selects all
+fn path_selected(path: &str, filters: &[String]) -> bool {
+    let path = display_path(path);
+    filters.is_empty()
+        || filters.iter().any(|filter| {
+            filter.is_empty() || path == filter || (path.starts_with(filter.as_str()) && path.as_bytes().get(filter.len()) == Some(&b'/'))
+        })
+}
+
+fn diff(args: &[String]) -> Result<(), ScmError> {
+    require_repo()?;
+    let scm_data = load_scm()?;
+    let mut context: Option<usize> = None;
+    let mut staged = false;
+    let mut color = io::stdout().is_terminal();
+    let mut specs: Vec<&String> = Vec::new();
+    let mut filters: Vec<String> = Vec::new();
+    let separated = args.iter().any(|arg| arg == "--");
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-u" | "--unified" => context = Some(3),
+            "--staged" | "--cached" => staged = true,
+            "--color" => color = true,
+            "--no-color" => color = false,
+            "--" => filters.extend(rest.by_ref().cloned()),
+            _ if arg.starts_with("-U") || arg.starts_with("--unified=") => {
+                let value = arg.trim_start_matches("-U").trim_start_matches("--unified=");
+                let lines = value
+                    .parse()
+                    .map_err(|_| ScmError::Usage(format!("Invalid context length '{}'", value)))?;
+                context = Some(lines);
+            }
+            _ if arg.starts_with('-') => return Err(ScmError::Usage(format!("Unknown diff option: {}", arg))),
+            // Leading arguments name commits until one does not; the rest are paths
+            _ if filters.is_empty() && specs.len() < 2 && diff_names_commit(&scm_data, arg) => {
+                if !separated && diff_names_path(&scm_data, arg) {
+                    return Err(ScmError::Usage(format!(
+                        "'{}' names both a commit and a path; put paths after '--'",
+                        arg
+                    )));
+                }
+                specs.push(arg);
+            }
+            _ => filters.push(arg.clone()),
+        }
+    }
+    let filters = normalize_paths(&filters);
+    
+    // Without commits, show unstaged changes, or staged ones with --staged
+    let old = match specs.first() {
+        Some(spec) => commit_files(&scm_data, spec)?,
+        None if staged => scm_data.latest.clone(),
+        None => staged_files(&scm_data)?,
+    };
+    let new = match specs.get(1) {
+        Some(spec) => commit_files(&scm_data, spec)?,
+        None if staged => staged_files(&scm_data)?,
+        None => {
+            // Untracked files are left out, as they are in `status`
+            let tracked = staged_files(&scm_data)?;
+            read_working_tree()?.into_iter().filter(|(path, _)| tracked.contains_key(path)).collect()
+        }
+    };
+    let changes = record_changes(Vec::new(), &old, &new);
+    let mut paths: Vec<(Option<&String>, Option<&String>)> = Vec::new();
+    for (from, to) in &changes.renamed {
+        paths.push((Some(from), Some(to)));
+    }
+    for path in changes.diff.keys().filter(|path| !changes.renamed.values().any(|to| to == *path)) {
+        paths.push((Some(path), Some(path)));
+    }
+    paths.extend(changes.init.keys().map(|path| (None, Some(path))));
+    paths.extend(changes.deleted.iter().map(|path| (Some(path), None)));
+    paths.retain(|(from, to)| [from, to].iter().any(|path| path.is_some_and(|p| path_selected(p, &filters))));
+    paths.sort_by_key(|(from, to)| to.or(*from).cloned());
+    
+    let empty = Vec::new();
+    if json_output() {
+        let files: Vec<_> = paths
+            .iter()
+            .map(|(from, to)| {
+                let old_lines = from.map_or(&empty, |path| &old[path]);
+                let new_lines = to.map_or(&empty, |path| &new[path]);
+                let binary = is_binary(old_lines) || is_binary(new_lines);
+                // The missing final newline is reported per side rather than as a line
+                let text = |lines: &[String]| -> Vec<String> {
+                    lines.iter().filter(|line| *line != NO_NEWLINE).cloned().collect()
+                };
+                let (old_text, new_text) = (text(old_lines), text(new_lines));
+                let hunks: Vec<_> = if binary {
+                    Vec::new()
+                } else {
+                    change_blocks(&edit_script(&old_text, &new_text))
+                        .into_iter()
+                        .map(|(i0, i1, j0, j1)| {
+                            json!({
+                                "old": i0 + 1,
+                                "new": j0 + 1,
+                                "removed": old_text[i0..i1],
+                                "added": new_text[j0..j1],
+                            })
+                        })
+                        .collect()
+                };
+                let no_newline = |lines: &[String]| lines.last().is_some_and(|line| line == NO_NEWLINE);
+                json!({
+                    "old_path": from.map(|path| display_path(path)),
+                    "new_path": to.map(|path| display_path(path)),
+                    "binary": binary,
+                    "old_no_newline": no_newline(old_lines),
+                    "new_no_newline": no_newline(new_lines),
+                    "hunks": hunks,
+                })
+            })
+            .collect();
+        report(&json!({ "files": files }));
+        return Ok(());
+    }
+    let mut out: Vec<(Paint, String)> = Vec::new();
+    for (from, to) in paths {
+        let old_name = display_path(from.or(to).map_or("", |path| path.as_str()));
+        let new_name = display_path(to.or(from).map_or("", |path| path.as_str()));
+        let old_lines = display_lines(from.map(|path| &old[path]).unwrap_or(&empty));
+        let new_lines = display_lines(to.map(|path| &new[path]).unwrap_or(&empty));
+        out.push((Paint::Header, format!("diff a/{} b/{}", old_name, new_name)));
+        match (from, to) {
+            (None, _) => out.push((Paint::Header, "new file".to_string())),
+            (_, None) => out.push((Paint::Header, "deleted file".to_string())),
+            _ if old_name != new_name => {
+                out.push((Paint::Header, format!("rename from {}", old_name)));
+                out.push((Paint::Header, format!("rename to {}", new_name)));
+            }
+            _ => {}
+        }
+        if old_lines == new_lines {
+            continue;
+        }
+        // Binary content is replaced as a whole rather than shown line by line
+        if is_binary(&old_lines) || is_binary(&new_lines) {
+            out.push((Paint::Plain, format!("Binary files a/{} and b/{} differ", old_name, new_name)));
+            continue;
+        }
+        match context {
+            Some(context) => {
+                let label = |path: Option<&String>, side: &str| match path {
+                    Some(path) => format!("{}/{}", side, display_path(path)),
+                    None => "/dev/null".to_string(),
+                };
+                out.push((Paint::Header, format!("--- {}", label(from, "a"))));
+                out.push((Paint::Header, format!("+++ {}", label(to, "b"))));
+                out.extend(unified_diff(&old_lines, &new_lines, context));
+            }
+            None => out.extend(normal_diff(&old_lines, &new_lines)),
+        }
+    }
+    
+    print_diff(&out, color);
+    Ok(())
+}
+
+/// Print lines to stdout, stopping quietly when the reader goes away
+fn print_lines(out: &[String]) {
+    let mut stdout = io::stdout().lock();
+    for line in out {
+        if writeln!(stdout, "{}", line).is_err() {
+            return;
+        }
+    }
+}
+
+/// Print diff output, colored by kind of line if asked to
+fn print_diff(out: &[(Paint, String)], color: bool) {
+    let mut stdout = io::stdout().lock();
+    for (paint, line) in out {
+        let written = if color && *paint != Paint::Plain {
+            writeln!(stdout, "{}{}\x1b[0m", paint.code(), line)
+        } else {
+            writeln!(stdout, "{}", line)
+        };
+        // Stop quietly when the reader (e.g. a pager) goes away
+        if written.is_err() {
+            return;
+        }
+    }
+}
+
+/// Print a Merkle inclusion proof for a commit as JSON
+fn prove(args: &[String]) -> Result<(), ScmError> {
+    require_repo()?;
+    let spec = args.first().ok_or_else(|| ScmError::Usage("Usage: scm prove <commit>".to_string()))?;
+    let scm_data = load_scm()?;
+    let index = resolve_commit(&scm_data, spec)?;
+    let proof = MerkleProof {
+        commit: scm_data.commits[index].hash.clone(),
+        index,
+        commits: scm_data.commits.len(),
+        root: merkle_root(&scm_data.merkle),
+        path: merkle_proof(&scm_data.merkle, index),
+    };
+    if json_output() {
+        report(&proof);
+    } else {
+        println!("{}", serde_json::to_string_pretty(&proof).unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// Check a proof from `scm prove` against a Merkle root, without needing the repository
+fn verify_proof(args: &[String]) -> Result<(), ScmError> {
+    let (root, file) = match args {
+        [root, file] => (root, file),
+        _ => return Err(ScmError::Usage("Usage: scm verify-proof <root> <proof-file | ->".to_string())),
+    };
+    let content = if file == "-" {
+        io::read_to_string(io::stdin())
+    } else {
+        fs::read_to_string(file)
+    };
+    let content = content.map_err(io_error(format!("cannot read proof {}", file)))?;
+    let proof: MerkleProof = parse_json(content.as_bytes(), &format!("proof {}", file))?;
+    let computed = merkle_root_from(&proof.commit, &proof.path);
+    if computed != *root {
+        return Err(ScmError::Refused(format!("Proof invalid: it leads to root {}", computed)));
+    }
+    if proof_index(&proof.path) != Some(proof.index) {
+        return Err(ScmError::Refused(format!(
+            "Proof invalid: its path does not lead up from commit #{}",
+            proof.index
+        )));
+    }
+    if !proof_fits(&proof.path, proof.index, proof.commits) {
+        return Err(ScmError::Refused(format!(
+            "Proof invalid: its path does not fit a history of {} commits",
+            proof.commits
+        )));
+    }
+    report(&json!({ "index": proof.index, "commit": proof.commit, "commits": proof.commits, "root": root }));
+    say(format!("Proof valid: commit #{} ({}) is included under root {}", proof.index, proof.commit, root));
+    Ok(())
+}
+
+/// Show the commit that introduced each line of a file, as of HEAD or another commit
+fn blame(args: &[String]) -> Result<(), ScmError> {
+    require_repo()?;
+    let (path, spec) = match args {
+        [path] => (path, None),
+        [path, spec] => (path, Some(spec)),
+        _ => return Err(ScmError::Usage("Usage: scm blame <file> [<commit>]".to_string())),
+    };
+    let mut scm_data = load_scm()?;
+    load_history(&mut scm_data)?;
+    let index = match spec {
+        Some(spec) => resolve_commit(&scm_data, spec)?,
+        None => head_index(&scm_data),
+    };
+    let key = format!("./{}", normalize_paths(std::slice::from_ref(path))[0]);
+    let files = reconstruct(&scm_data, index)?;
+    let lines = files
+        .get(&key)
+        .ok_or_else(|| ScmError::Refused(format!("{} is not tracked in commit #{}", display_path(&key), index)))?;
+    if is_binary(lines) {
+        return Err(ScmError::Refused(format!("{} is a binary file", display_path(&key))));
+    }
+    let origins = line_origins(&scm_data, index, &mut HashMap::new())?.remove(&key).unwrap_or_default();
+    if origins.len() != lines.len() {
+        return Err(ScmError::CorruptHistory(format!(
+            "diffs of {} replay to {} lines but commit #{} has {}",
+            display_path(&key),
+            origins.len(),
+            index,
+            lines.len()
+        )));
+    }
+    
+    if json_output() {
+        let blamed: Vec<_> = lines
+            .iter()
+            .zip(&origins)
+            .filter(|(line, _)| *line != NO_NEWLINE)
+            .enumerate()
+            .map(|(number, (line, idx))| {
+                let commit = &scm_data.commits[*idx];
+                json!({
+                    "line": number + 1,
+                    "text": line,
+                    "index": idx,
+                    "hash": commit.hash,
+                    "author": commit.author,
+                    "timestamp": commit.timestamp,
+                })
+            })
+            .collect();
+        report(&json!({ "path": display_path(&key), "index": index, "lines": blamed }));
+        return Ok(());
+    }
+    let idx_width = scm_data.commits.len().saturating_sub(1).to_string().len();
+    let author_width = origins.iter().map(|idx| scm_data.commits[*idx].author.chars().count()).max().unwrap_or(0);
+    let number_width = lines.len().to_string().len();
+    let mut out = Vec::new();
+    for (number, (line, idx)) in lines.iter().zip(&origins).filter(|(line, _)| *line != NO_NEWLINE).enumerate() {
+        let commit = &scm_data.commits[*idx];
+        let date = commit.timestamp.map(format_timestamp).unwrap_or_default();
+        out.push(format!(
+            "#{:<iw$} {} ({:<aw$} {:10} {:>nw$}) {}",
+            idx,
+            short(&commit.hash),
+            commit.author,
+            date.get(..10).unwrap_or(""),
+            number + 1,
+            line,
+            iw = idx_width,
+            aw = author_width,
+            nw = number_width
+        ));
+    }
+    print_lines(&out);
+    Ok(())
+}
+
+/// Check every object, commit hash, diff chain and the Merkle root; fails with corrupt history on problems
+fn fsck() -> Result<(), ScmError> {
+    if Path::new(SCM_DIR).is_file() {
+        migrate_legacy()?;
+    }
+    require_repo()?;
+    let mut problems: Vec<String> = Vec::new();
+    let list = fs::read_to_string(scm_path("commits")).map_err(io_error(".scm/commits"))?;
+    // A line without its newline is an append cut short, which loading ignores too
+    let lines: Vec<&str> = list.split_inclusive('\n').filter(|line| line.ends_with('\n')).collect();
+    let ids: Vec<String> = lines.iter().map(|line| line.split_whitespace().next().unwrap_or_default().to_string()).collect();
+    let mut commits = Vec::new();
+    let mut readable = Vec::new();
+    for (idx, id) in ids.iter().enumerate() {
+        let at = format!("commit #{} (line {} of .scm/commits)", idx, idx + 1);
+        // A tampered commit that still parses is checked further, to show what else is off
+        if let Err(e) = read_verified(id) {
+            problems.push(format!("{}: {}", at, e));
+        }
+        let parsed = read_object(id)
+            .and_then(|bytes| parse_json::<CommitEntry>(&bytes, &object_path(id).display().to_string()));
+        match parsed {
+            Ok(commit) => {
+                let summary = commit_list_line(id, &commit);
+                if lines[idx].split_whitespace().nth(1).is_some() && lines[idx].trim_end() != summary.trim_end() {
+                    problems.push(format!("{}: hash, tree or parents differ from the commit object", at));
+                }
+                commits.push(commit);
+                readable.push(true);
+            }
+            Err(e) => {
+                if read_object(id).is_ok() {
+                    problems.push(format!("{}: {}", at, e));
+                }
+                commits.push(CommitEntry::default());
+                readable.push(false);
+            }
+        }
+    }
+    let state: RepoState = match fs::read_to_string(scm_path("state")).map(|c| serde_json::from_str(&c)) {
+        Ok(Ok(state)) => state,
+        Ok(Err(e)) => {
+            problems.push(format!(".scm/state: {}", e));
+            RepoState::default()
+        }
+        Err(_) => RepoState::default(),
+    };
+    let hashes: Vec<String> = commits.iter().map(|c| c.hash.clone()).collect();
+    let data = ScmData {
+        latest: HashMap::new(),
+        commits,
+        merkle: build_merkle_tree(&hashes),
+        head: None,
+        branch: String::new(),
+        branches: BTreeMap::new(),
+        merging: None,
+        merge_added: Vec::new(),
+        conflicts: Vec::new(),
+        ids: ids.clone(),
+        index: None,
+        tags: BTreeMap::new(),
+        listed: None,
+        history_loaded: true,
+    };
+    
+    // Replaying in index order reuses each first parent's state, which always comes earlier
+    let mut replayed: Vec<Option<HashMap<String, Vec<String>>>> = Vec::new();
+    for (idx, commit) in data.commits.iter().enumerate() {
+        if !readable[idx] {
+            replayed.push(None);
+            continue;
+        }
+        let at = format!("commit #{} ({})", idx, object_path(&ids[idx]).display());
+        for parent in &commit.parents {
+            if !hashes[..idx].contains(parent) {
+                problems.push(format!("{}: parent {} is not an earlier commit", at, parent));
+            }
+        }
+        let base = match (first_parent(&data, idx), commit.parents.first()) {
+            (Some(parent), _) if parent < idx => replayed[parent].clone(),
+            (None, None) => Some(HashMap::new()),
+            _ => None,
+        };
+        let replay = base.ok_or_else(|| "its first parent could not be rebuilt".to_string()).and_then(|mut state| {
+            apply_commit(&mut state, commit, idx)?;
+            Ok(state)
+        });
+        let tree = read_verified_tree(&commit.tree);
+        match (&tree, &replay) {
+            (Ok(files), Ok(state)) if files != state => {
+                let mut differing: Vec<&str> = files
+                    .keys()
+                    .chain(state.keys())
+                    .filter(|path| files.get(*path) != state.get(*path))
+                    .map(|path| display_path(path))
+                    .collect();
+                differing.sort();
+                differing.dedup();
+                problems.push(format!("{}: diffs replay to different files than its tree: {}", at, differing.join(", ")));
+            }
+            (Err(e), _) => problems.push(format!("{}: tree: {}", at, e)),
+            _ => {}
+        }
+        if let Err(e) = &replay {
+            problems.push(format!("{}: cannot replay diffs: {}", at, e));
+        }
+        // Whichever snapshot survived must match the hash the commit was recorded with
+        if let Some(files) = tree.as_ref().ok().or(replay.as_ref().ok()) {
+            let actual = compute_hash(commit, files);
+            if actual != commit.hash {
+                problems.push(format!("{}: stored hash {} but content hashes to {}", at, commit.hash, actual));
+            }
+        }
+        replayed.push(replay.ok());
+    }
+    
+    let root = merkle_root(&data.merkle);
+    if !state.merkle_root.is_empty() && state.merkle_root != root {
+        problems.push(format!(".scm/state: Merkle root {} but the commits give {}", state.merkle_root, root));
+    }
+    for (name, hash) in &state.branches {
+        if !hashes.contains(hash) {
+            problems.push(format!(".scm/state: branch {} points to unknown commit {}", name, hash));
+        }
+    }
+    for (name, tag) in &state.tags {
+        if !hashes.contains(&tag.commit) {
+            problems.push(format!(".scm/state: tag {} points to unknown commit {}", name, tag.commit));
+        }
+    }
+    if let Some(head) = state.head.filter(|head| *head >= hashes.len()) {
+        problems.push(format!(".scm/state: HEAD is commit #{}, which does not exist", head));
+    }
+    for (path, blob) in state.index.iter().flatten() {
+        if let Err(e) = read_verified(blob) {
+            problems.push(format!(".scm/state: staged {}: {}", display_path(path), e));
+        }
+    }
+    for (n, id) in read_stash()?.iter().enumerate() {
+        let entry = read_verified(id).and_then(|bytes| parse_json::<CommitEntry>(&bytes, "stash entry"));
+        let staged = entry.and_then(|entry| match entry.staged.as_str() {
+            "" => Ok(()),
+            tree => read_verified_tree(tree).map(|_| ()),
+        });
+        if let Err(e) = staged {
+            problems.push(format!(".scm/stash: stash@{{{}}}: {}", n, e));
+        }
+    }
+    
+    report(&json!({ "commits": hashes.len(), "merkle_root": root, "problems": problems }));
+    if problems.is_empty() {
+        say(format!("Checked {} commits; no problems found", hashes.len()));
+        say(format!("Merkle Root: {}", root));
+    } else {
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        return Err(ScmError::CorruptHistory(format!(
+            "Checked {} commits; found {} problems",
+            hashes.len(),
+            problems.len()
+        )));
+    }
+    Ok(())
+}
+
+/// How `scm gc` stores each object still in use: in full, or as a delta against an object
+/// placed before it
+struct GcPlan {
+    /// Longest chain of deltas allowed, and the number of commits between full snapshots
+    every: usize,
+    order: Vec<String>,
+    /// Delta base, if any, and chain length of every object
+    placed: HashMap<String, (Option<String>, usize)>,
+}
+
+impl GcPlan {
+    /// Place an object against `base`, or in full if that would make its chain too long
+    fn place(&mut self, id: &str, base: Option<&str>) {
+        if id.is_empty() || self.placed.contains_key(id) {
+            return;
+        }
+        let (base, depth) = base
+            .filter(|base| *base != id)
+            .and_then(|base| self.placed.get(base).map(|(_, depth)| (Some(base.to_string()), depth + 1)))
+            .filter(|(_, depth)| *depth < self.every)
+            .unwrap_or((None, 0));
+        self.placed.insert(id.to_string(), (base, depth));
+        self.order.push(id.to_string());
+    }
+
+    /// Place a tree and its blobs against the tree of a parent, pairing blobs by path or by the
+    /// path they were renamed from
+    fn place_tree(
+        &mut self,
+        id: &str,
+        tree: &BTreeMap<String, String>,
+        parent: Option<(&str, &BTreeMap<String, String>)>,
+        renamed: &HashMap<String, String>,
+    ) {
+        let from: HashMap<&String, &String> = renamed.iter().map(|(old, new)| (new, old)).collect();
+        for (path, blob) in tree {
+            let base = parent.and_then(|(_, files)| files.get(*from.get(path).unwrap_or(&path)));
+            self.place(blob, base.map(String::as_str));
+        }
+        self.place(id, parent.map(|(tree, _)| tree));
+    }
+}
+
+/// Every file in the object and delta stores, with the object id its path spells and its size
+fn stored_files() -> Vec<(PathBuf, String, u64)> {
+    let mut files = Vec::new();
+    for store in ["objects", "deltas"] {
+        for dir in fs::read_dir(scm_path(store)).into_iter().flatten().flatten() {
+            let prefix = dir.file_name().to_string_lossy().to_string();
+            for entry in fs::read_dir(dir.path()).into_iter().flatten().flatten() {
+                let size = entry.metadata().map(|meta| meta.len()).unwrap_or(0);
+                files.push((entry.path(), format!("{}{}", prefix, entry.file_name().to_string_lossy()), size));
+            }
+        }
+    }
+    files
+}
+
+/// Remove objects nothing refers to, then store trees and blobs as deltas against the same
+/// file in the first parent, with a full snapshot every `--keyframes` commits
+fn gc(args: &[String]) -> Result<(), ScmError> {
+    let every = match args {
+        [] => KEYFRAME_INTERVAL,
+        [flag, n] if flag == "--keyframes" => match n.parse() {
+            Ok(n) if n > 0 => n,
+            _ => return Err(ScmError::Usage(format!("Invalid keyframe interval '{}'", n))),
+        },
+        _ => return Err(ScmError::Usage("Usage: scm gc [--keyframes <n>]".to_string())),
+    };
+    require_repo()?;
+    let _lock = lock_repo()?;
+    let mut scm_data = load_scm()?;
+    load_history(&mut scm_data)?;
+    let before: u64 = stored_files().iter().map(|(_, _, size)| size).sum();
+    
+    let mut plan = GcPlan { every, order: Vec::new(), placed: HashMap::new() };
+    let mut trees = Vec::new();
+    for (idx, commit) in scm_data.commits.iter().enumerate() {
+        plan.place(&scm_data.ids[idx], None);
+        let tree = tree_entries(&commit.tree)?;
+        // Keyframes start new chains, which bounds the deltas applied to read any commit
+        let parent = first_parent(&scm_data, idx).filter(|_| idx % every != 0);
+        let parent = parent.map(|p| (scm_data.commits[p].tree.as_str(), &trees[p]));
+        plan.place_tree(&commit.tree, &tree, parent, &commit.renamed);
+        for blob in commit.init.values() {
+            plan.place(blob, None);
+        }
+        trees.push(tree);
+    }
+    for id in read_stash()? {
+        let entry = read_commit(&id)?;
+        plan.place(&id, None);
+        let tree = tree_entries(&entry.tree)?;
+        let parent = entry.parents.first().and_then(|hash| index_of(&scm_data, hash));
+        let parent = parent.map(|p| (scm_data.commits[p].tree.as_str(), &trees[p]));
+        plan.place_tree(&entry.tree, &tree, parent, &entry.renamed);
+        if !entry.staged.is_empty() {
+            plan.place_tree(&entry.staged, &tree_entries(&entry.staged)?, Some((&entry.tree, &tree)), &HashMap::new());
+        }
+        for blob in entry.init.values() {
+            plan.place(blob, None);
+        }
+    }
+    for blob in scm_data.index.iter().flat_map(|index| index.values()) {
+        plan.place(blob, None);
+    }
+    
+    // Dropped stashes, unstaged files and interrupted writes leave objects nothing refers to
+    let mut unreachable = Vec::new();
+    for (path, id, _) in stored_files() {
+        if !plan.placed.contains_key(&id) {
+            fs::remove_file(&path).map_err(io_error(path.display()))?;
+            if is_object_id(&id) {
+                unreachable.push(id);
+            }
+        }
+    }
+    unreachable.sort();
+    unreachable.dedup();
+    // Deltas against a different base are stored in full again first, so that no chain can
+    // loop while objects are rewritten
+    for id in &plan.order {
+        let stored: Option<Delta> = fs::read(delta_path(id)).ok().and_then(|bytes| serde_json::from_slice(&bytes).ok());
+        match stored {
+            // A full copy beside a delta is left by an interrupted gc
+            Some(delta) if plan.placed[id].0.as_ref() == Some(&delta.base) => {
+                fs::remove_file(object_path(id)).ok();
+            }
+            Some(_) => {
+                if !object_path(id).exists() {
+                    write_stored(&object_path(id), &read_verified(id)?)?;
+                }
+                fs::remove_file(delta_path(id)).map_err(io_error(delta_path(id).display()))?;
+            }
+            None => {}
+        }
+    }
+    let mut deltas = 0;
+    for id in &plan.order {
+        let Some(base) = &plan.placed[id].0 else {
+            continue;
+        };
+        if delta_path(id).exists() {
+            deltas += 1;
+            continue;
+        }
+        let bytes = read_verified(id)?;
+        let base_bytes = read_verified(base)?;
+        let Some(ops) = make_delta(&base_bytes, &bytes) else {
+            continue;
+        };
+        // The full object is only dropped for a smaller delta that rebuilds it exactly
+        if apply_delta(&base_bytes, &ops).as_ref() != Some(&bytes) {
+            continue;
+        }
+        let delta = to_json(&Delta { base: base.clone(), ops });
+        if delta.len() >= bytes.len() {
+            continue;
+        }
+        write_stored(&delta_path(id), &delta)?;
+        fs::remove_file(object_path(id)).map_err(io_error(object_path(id).display()))?;
+        deltas += 1;
+    }
+    for store in ["objects", "deltas"] {
+        for dir in fs::read_dir(scm_path(store)).into_iter().flatten().flatten() {
+            fs::remove_dir(dir.path()).ok();
+        }
+    }
+    
+    let after: u64 = stored_files().iter().map(|(_, _, size)| size).sum();
+    let saved = before as i64 - after as i64;
+    report(&json!({
+        "objects": plan.order.len(),
+        "removed": unreachable.len(),
+        "deltas": deltas,
+        "keyframe_interval": every,
+        "bytes_before": before,
+        "bytes_after": after,
+        "bytes_saved": saved,
+    }));
+    say(format!("Removed {} unreachable objects", unreachable.len()));
+    say(format!(
+        "Stored {} of {} objects as deltas, with a full snapshot every {} commits",
+        deltas,
+        plan.order.len(),
+        every
+    ));
+    if saved >= 0 {
+        say(format!("Objects take {} bytes, down from {} ({} bytes saved)", after, before, saved));
+    } else {
+        say(format!("Objects take {} bytes, up from {} with fewer deltas", after, before));
+    }
+    Ok(())
+}
+
+/// Create a signing key in a new file and print the public key as a line for a trusted keys file
+fn keygen(args: &[String]) -> Result<(), ScmError> {
+    let [path] = args else {
+        return Err(ScmError::Usage("Usage: scm keygen <key-file>".to_string()));
+    };
+    let mut secret = [0u8; SECRET_KEY_LENGTH];
+    getrandom::getrandom(&mut secret).map_err(|e| ScmError::Io(format!("random number generator: {}", e)))?;
+    let key = SigningKey::from_bytes(&secret);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => ScmError::Refused(format!("{} already exists; not replacing a key", path)),
+        _ => ScmError::Io(format!("{}: {}", path, e)),
+    })?;
+    file.write_all(format!("{}\n", hex::encode(secret)).as_bytes())
+        .and_then(|_| file.sync_all())
+        .map_err(io_error(path))?;
+    say_err(format!("Wrote the secret key to {}; sign commits with 'scm config signing-key {}'", path, path));
+    let public_key = hex::encode(key.verifying_key().to_bytes());
+    let name = commit_author()?;
+    report(&json!({ "public_key": public_key, "name": name, "key_file": path }));
+    say(format!("{} {}", public_key, name));
+    Ok(())
+}
+
+/// Public keys from a trusted keys file, each with the name given after it
+fn load_trusted_keys(path: &str) -> Result<HashMap<String, (VerifyingKey, String)>, ScmError> {
+    let text = fs::read_to_string(path).map_err(io_error(path))?;
+    let mut keys = HashMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (hex_key, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let key = hex::decode(hex_key)
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+            .ok_or_else(|| ScmError::Parse(format!("{}:{}: not an Ed25519 public key", path, number + 1)))?;
+        keys.insert(hex_key.to_lowercase(), (key, name.trim().to_string()));
+    }
+    Ok(keys)
+}
+
+/// Check every commit's signature against the trusted keys. Signatures cover the commit hash,
+/// so each commit's hash is first recomputed from its message, author, parents and files
+fn verify(args: &[String]) -> Result<(), ScmError> {
+    require_repo()?;
+    let mut keys_file = scm_path(TRUSTED_KEYS).display().to_string();
+    let mut require_signed = false;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--keys" => match rest.next() {
+                Some(path) => keys_file = path.clone(),
+                None => return Err(ScmError::Usage("Option --keys needs a file".to_string())),
+            },
+            "--require-signed" => require_signed = true,
+            _ => return Err(ScmError::Usage(format!("Unknown option for verify: {}", arg))),
+        }
+    }
+    let trusted = load_trusted_keys(&keys_file)?;
+    let mut scm_data = load_scm()?;
+    load_history(&mut scm_data)?;
+    
+    let mut forged = 0;
+    let mut problems = Vec::new();
+    let mut unsigned = 0;
+    let mut checked = Vec::new();
+    for (idx, commit) in scm_data.commits.iter().enumerate() {
+        let at = format!("#{} {}...", idx, short(&commit.hash));
+        // A commit edited after it was made keeps its hash and so its signature
+        let content = reconstruct(&scm_data, idx).map(|files| compute_hash(commit, &files) == commit.hash);
+        if !matches!(content, Ok(true)) {
+            forged += 1;
+            checked.push(json!({ "index": idx, "hash": commit.hash, "status": "corrupt" }));
+            match content {
+                Err(e) => problems.push(format!("{}: cannot rebuild its files: {}", at, e)),
+                _ => problems.push(format!("{}: content does not match its hash; it was changed after it was made", at)),
+            }
+            continue;
+        }
+        let Some(signature) = &commit.signature else {
+            unsigned += 1;
+            checked.push(json!({ "index": idx, "hash": commit.hash, "status": "unsigned" }));
+            if require_signed {
+                problems.push(format!("{}: not signed", at));
+            } else {
+                say(format!("{} not signed", at));
+            }
+            continue;
+        };
+        let Some((key, name)) = trusted.get(&signature.key.to_lowercase()) else {
+            checked.push(json!({ "index": idx, "hash": commit.hash, "status": "untrusted", "key": signature.key }));
+            problems.push(format!("{}: signed by untrusted key {}", at, short(&signature.key)));
+            continue;
+        };
+        let valid = hex::decode(&signature.sig)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .is_some_and(|sig| key.verify(commit.hash.as_bytes(), &sig).is_ok());
+        if valid {
+            checked.push(json!({ "index": idx, "hash": commit.hash, "status": "good", "key": signature.key, "signer": name }));
+            say(format!("{} good signature by {}", at, if name.is_empty() { short(&signature.key) } else { name }));
+        } else {
+            forged += 1;
+            checked.push(json!({ "index": idx, "hash": commit.hash, "status": "bad", "key": signature.key }));
+            problems.push(format!("{}: bad signature claiming key {}", at, short(&signature.key)));
+        }
+    }
+    report(&json!({ "commits": checked, "problems": problems }));
+    for problem in &problems {
+        say_err(problem);
+    }
+    
+    let summary = format!(
+        "Checked {} commits: {} signed, {} unsigned",
+        scm_data.commits.len(),
+        scm_data.commits.len() - unsigned,
+        unsigned
+    );
+    if forged > 0 {
+        Err(ScmError::CorruptHistory(format!("{}; {} forged or corrupt", summary, forged)))
+    } else if !problems.is_empty() {
+        Err(ScmError::Refused(format!("{}; {} not verified", summary, problems.len())))
+    } else {
+        say(summary);
+        Ok(())
+    }
+}
+
+/// Path as shown to the user, without the leading `./`
+fn display_path(path: &str) -> &str {
+    path.strip_prefix("./").unwrap_or(path)
+}
+
+/// Changes between two sets of files as status codes and paths
+fn file_changes(old: &HashMap<String, Vec<String>>, new: &HashMap<String, Vec<String>>) -> Vec<(char, String)> {
+    let changes = record_changes(Vec::new(), old, new);
+    let mut entries: Vec<(char, String)> = Vec::new();
+    entries.extend(changes.init.keys().map(|path| ('A', display_path(path).to_string())));
+    entries.extend(changes.diff.keys().map(|path| ('M', display_path(path).to_string())));
+    entries.extend(changes.deleted.iter().map(|path| ('D', display_path(path).to_string())));
+    entries.extend(
+        changes
+            .renamed
+            .iter()
+            .map(|(from, to)| ('R', format!("{} -> {}", display_path(from), display_path(to)))),
+    );
+    entries
+}
+
+/// Staged changes (against the checked-out commit), unstaged changes to staged files and
+/// untracked files, as a staged code, an unstaged code and a path, sorted by path
+fn working_changes(scm_data: &ScmData) -> Result<Vec<(char, char, String)>, ScmError> {
+    let staged = staged_files(scm_data)?;
+    let working = read_working_tree()?;
+    let mut entries: Vec<(char, char, String)> =
+        file_changes(&scm_data.latest, &staged).into_iter().map(|(code, path)| (code, ' ', path)).collect();
+    for (path, lines) in &staged {
+        let code = match working.get(path) {
+            None => 'D',
+            Some(current) if current != lines => 'M',
+            Some(_) => continue,
+        };
+        let name = display_path(path);
+        match entries.iter_mut().find(|entry| entry.2 == name || entry.2.ends_with(&format!(" -> {}", name))) {
+            Some(entry) => entry.1 = code,
+            None => entries.push((' ', code, name.to_string())),
+        }
+    }
+    entries.extend(
+        working
+            .keys()
+            .filter(|path| !staged.contains_key(*path))
+            .map(|path| ('?', '?', display_path(path).to_string())),
+    );
+    entries.sort_by(|a, b| a.2.cmp(&b.2));
+    Ok(entries)
+}
+
+/// Word for a status code in the long status format
+fn status_label(code: char) -> &'static str {
+    match code {
+        'A' => "added",
+        'M' => "modified",
+        'D' => "deleted",
+        _ => "renamed",
+    }
+}
+
+/// Show the checked-out commit and uncommitted changes; exits with the dirty tree code when there
+/// are any, as commands refused for a dirty tree do
+fn status(args: &[String]) -> Result<(), ScmError> {
+    if !Path::new(SCM_DIR).exists() {
+        report(&json!({ "repository": false }));
+        say("Not under version control. Run 'scm init' to initialize.");
+        return Ok(());
+    }
+    let short_format = args.iter().any(|arg| arg == "--short" || arg == "-s");
+    let scm_data = load_scm()?;
+    let changes = working_changes(&scm_data)?;
+    if json_output() {
+        let entries: Vec<_> = changes
+            .iter()
+            .map(|(staged, unstaged, path)| json!({ "staged": staged, "unstaged": unstaged, "path": path }))
+            .collect();
+        report(&json!({
+            "repository": true,
+            "commits": scm_data.commits.len(),
+            "tracked_files": scm_data.latest.len(),
+            "branch": if scm_data.head.is_none() { Some(&scm_data.branch) } else { None },
+            "detached": scm_data.head,
+            "hash": scm_data.commits.get(head_index(&scm_data)).map(|commit| &commit.hash),
+            "merging": scm_data.merging,
+            "conflicts": scm_data.conflicts.iter().map(|path| display_path(path)).collect::<Vec<_>>(),
+            "clean": changes.is_empty(),
+            "entries": entries,
+        }));
+    } else if short_format {
+        for (staged, unstaged, path) in &changes {
+            println!("{}{} {}", staged, unstaged, path);
+        }
+    } else {
+        println!("SCM Status:");
+        println!("Total commits: {}", scm_data.commits.len());
+        println!("Tracked files: {}", scm_data.latest.len());
+        match scm_data.head {
+            Some(head) => println!("HEAD detached at #{}", head),
+            None => println!("On branch {}", scm_data.branch),
+        }
+        if let Some(commit) = scm_data.commits.get(head_index(&scm_data)) {
+            println!("Commit hash: {}...", short(&commit.hash));
+        }
+        if let Some(theirs) = &scm_data.merging {
+            println!("Merging {}...; commit to conclude or run 'scm merge --abort'", short(theirs));
+        }
+        if !scm_data.conflicts.is_empty() {
+            println!("\nUnresolved conflicts (stage each with 'scm add' once resolved):");
+            for path in &scm_data.conflicts {
+                println!("  {}", display_path(path));
+            }
+        }
+        let staged: Vec<_> = changes.iter().filter(|(code, _, _)| !matches!(code, ' ' | '?')).collect();
+        let unstaged: Vec<_> = changes.iter().filter(|(code, unstaged, _)| *code != '?' && *unstaged != ' ').collect();
+        let untracked: Vec<_> = changes.iter().filter(|(code, _, _)| *code == '?').collect();
+        if changes.is_empty() {
+            println!("\nNothing to commit, working tree clean");
+        }
+        if !staged.is_empty() {
+            println!("\nChanges to be committed:");
+            for (code, _, path) in staged {
+                println!("  {:<10}{}", format!("{}:", status_label(*code)), path);
+            }
+        }
+        if !unstaged.is_empty() {
+            println!("\nChanges not staged for commit:");
+            for (_, code, path) in unstaged {
+                println!("  {:<10}{}", format!("{}:", status_label(*code)), path);
+            }
+        }
+        if !untracked.is_empty() {
+            println!("\nUntracked files:");
+            for (_, _, path) in untracked {
+                println!("  {}", path);
+            }
+        }
+    }
+    // A dirty tree is reported, not an error, so nothing is printed to stderr
+    if !changes.is_empty() {
+        finish(ScmError::DirtyTree(String::new()).exit_code());
+    }
+    Ok(())
+}
+
+/// Absolute path of another repository named on the command line
+fn remote_root(path: &str) -> Result<PathBuf, ScmError> {
+    let root = fs::canonicalize(path).map_err(io_error(path))?;
+    if !root.join(SCM_DIR).exists() {
+        return Err(ScmError::Refused(format!("{} is not a repository", path)));
+    }
+    if env::current_dir().and_then(fs::canonicalize).is_ok_and(|here| here == root) {
+        return Err(ScmError::Usage(format!("{} is this repository", path)));
+    }
+    Ok(root)
+}
+
+/// Run `f` in another repository, since every path used here is relative to the repository root
+fn in_repo<T>(root: &Path, f: impl FnOnce() -> Result<T, ScmError>) -> Result<T, ScmError> {
+    let home = env::current_dir().map_err(io_error("current directory"))?;
+    env::set_current_dir(root).map_err(io_error(root.display()))?;
+    let result = f();
+    env::set_current_dir(&home).map_err(io_error(home.display()))?;
+    result
+}
+
+/// Indices of the commits `from` has and `to` lacks, parents before children. Equal Merkle
+/// roots mean equal histories, and when `to` is a prefix of `from` its root is the root of
+/// that many of `from`'s commits, so only the hashes of diverged histories are compared
+fn missing_commits(from: &ScmData, to: &ScmData) -> Vec<usize> {
+    let root = merkle_root(&to.merkle);
+    if merkle_root(&from.merkle) == root {
+        return Vec::new();
+    }
+    let hashes: Vec<String> = from.commits.iter().map(|c| c.hash.clone()).collect();
+    let shared = to.commits.len();
+    if shared <= hashes.len() && merkle_root(&build_merkle_tree(&hashes[..shared])) == root {
+        return (shared..hashes.len()).collect();
+    }
+    (0..hashes.len()).filter(|idx| index_of(to, &hashes[*idx]).is_none()).collect()
+}
+
+/// Whether `id` has the form of an object id, so it is safe to use as a path
+fn is_object_id(id: &str) -> bool {
+    id.len() == 128 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Objects by id, as exchanged between repositories
+type Objects = HashMap<String, Vec<u8>>;
+
+/// Read objects from the store of the repository at `root`
+fn read_objects(root: &Path, ids: &[String]) -> Result<Objects, ScmError> {
+    let mut objects = HashMap::new();
+    for id in ids {
+        objects.insert(id.clone(), load_object(root, id)?);
+    }
+    Ok(objects)
+}
+
+/// Every object a commit needs, as stored in the current repository: its commit object, tree
+/// and blobs
+fn commit_objects(scm_data: &ScmData, index: usize) -> Result<Vec<String>, ScmError> {
+    let mut ids = vec![scm_data.ids[index].clone()];
+    let tree = &scm_data.commits[index].tree;
+    if !tree.is_empty() {
+        ids.push(tree.clone());
+        ids.extend(tree_entries(tree)?.into_values());
+    }
+    Ok(ids)
+}
+
+/// Blob id of every path in a tree object; commits from before trees existed have none
+fn tree_entries(id: &str) -> Result<BTreeMap<String, String>, ScmError> {
+    if id.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    parse_json(&read_object(id)?, &format!("tree {}", object_path(id).display()))
+}
+
+/// Append commits another repository has, given as their index there and object id, to `to`
+/// in memory, storing the objects `fetch` returns for them; each commit is checked against its
+/// hash, and the commit list is left for the caller to write
+fn receive_commits(
+    source: &str,
+    wanted: &[(usize, String)],
+    fetch: &mut dyn FnMut(&[String]) -> Result<Objects, ScmError>,
+    to: &mut ScmData,
+) -> Result<usize, ScmError> {
+    // Objects arrive in three rounds, as trees are only known from commits and blobs from trees
+    let mut take = |ids: Vec<String>| -> Result<Objects, ScmError> {
+        let ids: Vec<String> = ids.into_iter().filter(|id| !has_object(id)).collect();
+        let mut objects = if ids.is_empty() { HashMap::new() } else { fetch(&ids)? };
+        let mut taken = HashMap::new();
+        for id in ids {
+            let bytes = objects
+                .remove(&id)
+                .ok_or_else(|| ScmError::CorruptHistory(format!("{} did not send object {}", source, id)))?;
+            if object_id(&bytes) != id {
+                return Err(ScmError::CorruptHistory(format!("object {} from {} does not match its id", id, source)));
+            }
+            taken.insert(id, bytes);
+        }
+        Ok(taken)
+    };
+    let fetched_commits = take(wanted.iter().map(|(_, id)| id.clone()).collect())?;
+    let mut commits = Vec::new();
+    for (_, id) in wanted {
+        let bytes = match fetched_commits.get(id) {
+            Some(bytes) => bytes.clone(),
+            None => read_object(id)?,
+        };
+        commits.push(parse_json::<CommitEntry>(&bytes, &format!("commit {} from {}", id, source))?);
+    }
+    let fetched_trees = take(commits.iter().map(|c| c.tree.clone()).filter(|tree| !tree.is_empty()).collect())?;
+    let mut blobs = Vec::new();
+    for bytes in fetched_trees.values() {
+        let tree: BTreeMap<String, String> = parse_json(bytes, &format!("tree from {}", source))?;
+        blobs.extend(tree.into_values());
+    }
+    blobs.sort();
+    blobs.dedup();
+    // Blobs before trees before commits, so a stored object never refers to a missing one
+    let fetched_blobs = take(blobs)?;
+    for bytes in fetched_blobs.values().chain(fetched_trees.values()).chain(fetched_commits.values()) {
+        write_object(bytes)?;
+    }
+    
+    let start = to.commits.len();
+    for (&(idx, ref id), commit) in wanted.iter().zip(commits) {
+        // Commits without parents follow the previous commit, so they must keep their position
+        if commit.parents.is_empty() && to.commits.len() != idx {
+            return Err(ScmError::Refused(format!(
+                "{} has history unrelated to this repository (commit #{} has no parent)",
+                source, idx
+            )));
+        }
+        to.ids.push(id.clone());
+        to.commits.push(commit);
+    }
+    for idx in start..to.commits.len() {
+        let files = reconstruct(to, idx)?;
+        if compute_hash(&to.commits[idx], &files) != to.commits[idx].hash {
+            return Err(ScmError::CorruptHistory(format!(
+                "commit {} from {} does not match its hash",
+                short(&to.commits[idx].hash),
+                source
+            )));
+        }
+    }
+    let hashes: Vec<String> = to.commits.iter().map(|c| c.hash.clone()).collect();
+    to.merkle = build_merkle_tree(&hashes);
+    Ok(wanted.len())
+}
+
+/// What a repository tells others about itself before commits are exchanged
+#[derive(Serialize, Deserialize, Debug)]
+struct RemoteInfo {
+    /// Directory name, used to name clones
+    name: String,
+    branch: String,
+    branches: BTreeMap<String, String>,
+    #[serde(default)]
+    tags: BTreeMap<String, Tag>,
+    commits: usize,
+    root: String,
+}
+
+fn remote_info(scm_data: &ScmData) -> RemoteInfo {
+    let dir = env::current_dir().unwrap_or_default();
+    RemoteInfo {
+        name: dir.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default(),
+        branch: scm_data.branch.clone(),
+        branches: scm_data.branches.clone(),
+        tags: scm_data.tags.clone(),
+        commits: scm_data.commits.len(),
+        root: merkle_root(&scm_data.merkle),
+    }
+}
+
+/// A commit as listed by `scm serve`: its hash, which is its Merkle leaf, and its object id
+#[derive(Serialize, Deserialize, Debug)]
+struct CommitRef {
+    hash: String,
+    id: String,
+}
+
+/// Commits and objects sent by `scm push` to a server, to fast-forward `branch` to `tip`
+#[derive(Serialize, Deserialize, Debug)]
+struct PushRequest {
+    branch: String,
+    tip: String,
+    #[serde(default)]
+    tags: BTreeMap<String, Tag>,
+    /// Index in the sender's history and object id of every commit sent
+    commits: Vec<(usize, String)>,
+    /// Object ids and their bytes as hex
+    objects: HashMap<String, String>,
+}
+
+/// Answer from `scm serve` when a request fails, and the error in `--json` output
+#[derive(Serialize, Deserialize, Debug)]
+struct ErrorReply {
+    error: String,
+    code: i32,
+}
+
+/// Another repository to exchange commits with: a directory on this machine, or the address
+/// of an `scm serve`
+enum Remote {
+    Local(PathBuf),
+    Server(String),
+}
+
+impl Remote {
+    fn open(spec: &str) -> Result<Remote, ScmError> {
+        match spec.strip_prefix("http://") {
+            Some(address) => Ok(Remote::Server(address.trim_end_matches('/').to_string())),
+            None => remote_root(spec).map(Remote::Local),
+        }
+    }
+    
+    fn info(&self) -> Result<RemoteInfo, ScmError> {
+        match self {
+            Remote::Local(root) => in_repo(root, || Ok(remote_info(&load_scm()?))),
+            Remote::Server(address) => parse_json(&http_request(address, "GET", "/info", None)?, "reply to /info"),
+        }
+    }
+    
+    /// Commits the remote has and `ours` lacks, as their index there and object id
+    fn wanted(&self, ours: &ScmData, info: &RemoteInfo) -> Result<Vec<(usize, String)>, ScmError> {
+        match self {
+            Remote::Local(root) => {
+                let theirs = in_repo(root, load_scm)?;
+                Ok(missing_commits(&theirs, ours).into_iter().map(|idx| (idx, theirs.ids[idx].clone())).collect())
+            }
+            Remote::Server(address) => {
+                if info.root == merkle_root(&ours.merkle) {
+                    return Ok(Vec::new());
+                }
+                let shared = common_prefix(address, ours, info.commits)?;
+                let suffix = server_commits(address, shared)?;
+                Ok(suffix
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, commit)| index_of(ours, &commit.hash).is_none())
+                    .map(|(i, commit)| (shared + i, commit.id))
+                    .collect())
+            }
+        }
+    }
+    
+    fn fetch(&self, ids: &[String]) -> Result<Objects, ScmError> {
+        match self {
+            Remote::Local(root) => read_objects(root, ids),
+            Remote::Server(address) => {
+                let reply = http_request(address, "POST", "/objects", Some(&to_json(&ids)))?;
+                let objects: HashMap<String, String> = parse_json(&reply, "reply to /objects")?;
+                objects
+                    .into_iter()
+                    .map(|(id, bytes)| match hex::decode(&bytes) {
+                        Ok(bytes) => Ok((id, bytes)),
+                        Err(e) => Err(ScmError::Parse(format!("object {} from {}: {}", id, address, e))),
+                    })
+                    .collect()
+            }
+        }
+    }
+    
+    /// Send the commits the remote lacks and fast-forward its `name` to the tip of ours;
+    /// `None` means it was already there
+    fn push(&self, ours: &ScmData, name: &str, tip: &str) -> Result<Option<usize>, ScmError> {
+        let home = env::current_dir().map_err(io_error("current directory"))?;
+        match self {
+            Remote::Local(root) => in_repo(root, || {
+                accept_push(name, tip, &ours.tags, &mut |theirs| {
+                    let wanted: Vec<(usize, String)> =
+                        missing_commits(ours, theirs).into_iter().map(|idx| (idx, ours.ids[idx].clone())).collect();
+                    receive_commits(&home.display().to_string(), &wanted, &mut |ids| read_objects(&home, ids), theirs)
+                })
+            }),
+            Remote::Server(address) => {
+                let info = self.info()?;
+                let mut commits = Vec::new();
+                if info.root != merkle_root(&ours.merkle) {
+                    let shared = common_prefix(address, ours, info.commits)?;
+                    let theirs: Vec<String> = server_commits(address, shared)?.into_iter().map(|c| c.hash).collect();
+                    commits = (shared..ours.commits.len())
+                        .filter(|idx| !theirs.contains(&ours.commits[*idx].hash))
+                        .map(|idx| (idx, ours.ids[idx].clone()))
+                        .collect();
+                }
+                let mut objects = HashMap::new();
+                for (idx, _) in &commits {
+                    for id in commit_objects(ours, *idx)? {
+                        if let Entry::Vacant(entry) = objects.entry(id) {
+                            let bytes = read_object(entry.key())?;
+                            entry.insert(hex::encode(bytes));
+                        }
+                    }
+                }
+                let request = PushRequest {
+                    branch: name.to_string(),
+                    tip: tip.to_string(),
+                    tags: ours.tags.clone(),
+                    commits,
+                    objects,
+                };
+                let reply = http_request(address, "POST", "/push", Some(&to_json(&request)))?;
+                parse_json(&reply, "reply to /push")
+            }
+        }
+    }
+}
+
+/// Connect to the first address `address` resolves to that answers in time
+fn connect_server(address: &str) -> io::Result<TcpStream> {
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "address resolves to nothing");
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, NETWORK_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+/// Send one HTTP request to `scm serve` and return the body of a successful reply; a failure
+/// reported by the server comes back as the error it ran into
+fn http_request(address: &str, method: &str, path: &str, body: Option<&[u8]>) -> Result<Vec<u8>, ScmError> {
+    let url = format!("http://{}{}", address, path);
+    // A server that stops answering must not hang the command, which holds the repository lock
+    let mut stream = connect_server(address).map_err(io_error(&url))?;
+    stream.set_read_timeout(Some(NETWORK_TIMEOUT)).map_err(io_error(&url))?;
+    stream.set_write_timeout(Some(NETWORK_TIMEOUT)).map_err(io_error(&url))?;
+    let body = body.unwrap_or_default();
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        path,
+        address,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).map_err(io_error(&url))?;
+    stream.write_all(body).map_err(io_error(&url))?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).map_err(io_error(&url))?;
+    let split = reply
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| ScmError::Parse(format!("reply from {}: no end of headers", url)))?;
+    let status_line = String::from_utf8_lossy(&reply[..split]).lines().next().unwrap_or_default().to_string();
+    let body = reply[split + 4..].to_vec();
+    match status_line.split_whitespace().nth(1) {
+        Some("200") => Ok(body),
+        _ => match serde_json::from_slice::<ErrorReply>(&body) {
+            Ok(reply) => Err(ScmError::from_exit_code(reply.code, format!("{}: {}", address, reply.error))),
+            Err(_) => Err(ScmError::Io(format!("{}: {}", url, status_line))),
+        },
+    }
+}
+
+/// Length of the longest common prefix of our commit list and the server's. Descending from
+/// the top, each step asks for one Merkle node; a node covering a block of leaves present in
+/// both histories is equal in both exactly when those leaves are
+fn common_prefix(address: &str, ours: &ScmData, their_len: usize) -> Result<usize, ScmError> {
+    let both = ours.commits.len().min(their_len);
+    let mut shared = 0;
+    for level in (0..ours.merkle.len()).rev() {
+        let span = 1 << level;
+        if shared + span > both {
+            continue;
+        }
+        let pos = shared / span;
+        let path = format!("/merkle/{}/{}/{}", level, pos, pos + 1);
+        let nodes: Vec<String> = parse_json(&http_request(address, "GET", &path, None)?, "reply to /merkle")?;
+        if nodes.first() == ours.merkle[level].get(pos) {
+            shared += span;
+        }
+    }
+    Ok(shared)
+}
+
+/// The server's commits from index `from` on
+fn server_commits(address: &str, from: usize) -> Result<Vec<CommitRef>, ScmError> {
+    parse_json(&http_request(address, "GET", &format!("/commits/{}", from), None)?, "reply to /commits")
+}
+
+/// Add the tags another repository has and this one lacks, if their commits are here; tags
+/// that differ under the same name are left as they are
+fn take_tags(scm_data: &mut ScmData, tags: &BTreeMap<String, Tag>, source: &str) -> Vec<String> {
+    let mut taken = Vec::new();
+    for (name, tag) in tags {
+        match scm_data.tags.get(name) {
+            Some(ours) if ours != tag => eprintln!("Tag {} differs on {}; keeping this repository's", name, source),
+            Some(_) => {}
+            None if index_of(scm_data, &tag.commit).is_some() => {
+                scm_data.tags.insert(name.clone(), tag.clone());
+                taken.push(name.clone());
+            }
+            None => {}
+        }
+    }
+    taken
+}
+
+/// Fast-forward branch `name` of the current repository to `tip` once `receive` has added the
+/// commits it needs, updating the working tree if the branch is checked out, and take the
+/// sender's new tags; `None` means the branch was already there
+fn accept_push(
+    name: &str,
+    tip: &str,
+    tags: &BTreeMap<String, Tag>,
+    receive: &mut dyn FnMut(&mut ScmData) -> Result<usize, ScmError>,
+) -> Result<Option<usize>, ScmError> {
+    if !valid_branch_name(name) {
+        return Err(ScmError::Usage(format!("Invalid branch name '{}'", name)));
+    }
+    let _lock = lock_repo()?;
+    let mut scm_data = load_scm()?;
+    let previous = scm_data.branches.get(name).cloned();
+    if previous.as_deref() == Some(tip) {
+        if !take_tags(&mut scm_data, tags, "the pushing repository").is_empty() {
+            save_scm(&scm_data)?;
+        }
+        return Ok(None);
+    }
+    let checked_out = scm_data.head.is_none() && scm_data.branch == name;
+    if checked_out && (has_local_changes(&scm_data, &read_working_tree()?) || scm_data.merging.is_some()) {
+        return Err(ScmError::DirtyTree(format!(
+            "The receiving repository has uncommitted changes on {}, which it has checked out",
+            name
+        )));
+    }
+    let count = receive(&mut scm_data)?;
+    let new_tip = index_of(&scm_data, tip)
+        .ok_or_else(|| ScmError::MissingCommit(format!("The push did not include its tip {}", short(tip))))?;
+    // Fast-forward only: the old tip must be part of the history being pushed
+    if let Some(previous) = &previous {
+        if index_of(&scm_data, previous).is_none_or(|old| merge_base(&scm_data, old, new_tip) != Some(old)) {
+            return Err(ScmError::Refused(format!(
+                "Rejected non-fast-forward push: {} has commits that the pushed history lacks; pull and merge first",
+                name
+            )));
+        }
+    }
+    write_commit_list(&mut scm_data)?;
+    scm_data.branches.insert(name.to_string(), tip.to_string());
+    take_tags(&mut scm_data, tags, "the pushing repository");
+    if checked_out {
+        check_out_tree(&mut scm_data, new_tip, false)?;
+    }
+    save_scm(&scm_data)?;
+    Ok(Some(count))
+}
+
+/// Copy another repository, on this machine or served by `scm serve`, into a new directory and
+/// check out its branch
+fn clone(args: &[String]) -> Result<(), ScmError> {
+    let (spec, dir) = match args {
+        [spec] => (spec, None),
+        [spec, dir] => (spec, Some(PathBuf::from(dir))),
+        _ => return Err(ScmError::Usage("Usage: scm clone <path | http://host:port> [<dir>]".to_string())),
+    };
+    let remote = Remote::open(spec)?;
+    let info = remote.info()?;
+    let dir = dir.unwrap_or_else(|| PathBuf::from(if info.name.is_empty() { "clone" } else { &info.name }));
+    if fs::read_dir(&dir).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(ScmError::Refused(format!("{} already exists and is not empty", dir.display())));
+    }
+    fs::create_dir_all(dir.join(SCM_DIR).join("objects")).map_err(io_error(dir.display()))?;
+    in_repo(&dir, || {
+        let _lock = lock_repo()?;
+        let mut scm_data = ScmData {
+            latest: HashMap::new(),
+            commits: Vec::new(),
+            merkle: Vec::new(),
+            head: None,
+            branch: info.branch.clone(),
+            branches: info.branches.clone(),
+            merging: None,
+            merge_added: Vec::new(),
+            conflicts: Vec::new(),
+            ids: Vec::new(),
+            index: None,
+            tags: info.tags.clone(),
+            listed: None,
+            history_loaded: true,
+        };
+        let wanted = remote.wanted(&scm_data, &info)?;
+        let count = receive_commits(spec, &wanted, &mut |ids| remote.fetch(ids), &mut scm_data)?;
+        if count == 0 {
+            return Err(ScmError::Refused(format!("{} has no commits", spec)));
+        }
+        write_commit_list(&mut scm_data)?;
+        let tip = head_index(&scm_data);
+        check_out_tree(&mut scm_data, tip, false)?;
+        save_scm(&scm_data)?;
+        report(&json!({ "commits": count, "directory": dir, "branch": scm_data.branch, "index": tip }));
+        say(format!("Cloned {} commits into {} (branch {})", count, dir.display(), scm_data.branch));
+        Ok(())
+    })
+}
+
+/// Fetch the commits another repository has and this one lacks, then fast-forward every
+/// branch that has only moved ahead there
+fn pull(args: &[String]) -> Result<(), ScmError> {
+    let [spec] = args else {
+        return Err(ScmError::Usage("Usage: scm pull <path | http://host:port>".to_string()));
+    };
+    require_repo()?;
+    let remote = Remote::open(spec)?;
+    let _lock = lock_repo()?;
+    let mut scm_data = load_scm()?;
+    let info = remote.info()?;
+    let wanted = remote.wanted(&scm_data, &info)?;
+    // Commits are only added in memory until the branch updates are known to be possible
+    let count = receive_commits(spec, &wanted, &mut |ids| remote.fetch(ids), &mut scm_data)?;
+    
+    let mut updates: Vec<(String, String)> = Vec::new();
+    for (name, their_hash) in &info.branches {
+        let Some(their_tip) = index_of(&scm_data, their_hash) else {
+            continue;
+        };
+        match branch_index(&scm_data, name) {
+            None => updates.push((name.clone(), their_hash.clone())),
+            Some(our_tip) if our_tip == their_tip => {}
+            // Behind: fast-forward. Ahead: nothing to take. Otherwise the branch has diverged
+            Some(our_tip) if merge_base(&scm_data, our_tip, their_tip) == Some(our_tip) => {
+                updates.push((name.clone(), their_hash.clone()))
+            }
+            Some(our_tip) if merge_base(&scm_data, our_tip, their_tip) == Some(their_tip) => {}
+            Some(_) => eprintln!(
+                "Branch {} has diverged from {}; merge its tip with 'scm merge {}'",
+                name,
+                spec,
+                short(their_hash)
+            ),
+        }
+    }
+    let moves_checkout = scm_data.head.is_none() && updates.iter().any(|(name, _)| *name == scm_data.branch);
+    if moves_checkout && (has_local_changes(&scm_data, &read_working_tree()?) || scm_data.merging.is_some()) {
+        return Err(ScmError::DirtyTree(format!(
+            "Working tree has uncommitted changes; commit them before pulling into {}",
+            scm_data.branch
+        )));
+    }
+    
+    write_commit_list(&mut scm_data)?;
+    let mut moved = Vec::new();
+    for (name, hash) in updates {
+        let from = branch_index(&scm_data, &name);
+        let to = index_of(&scm_data, &hash);
+        let shown = to.map(|idx| format!("#{}", idx)).unwrap_or_default();
+        match from {
+            Some(from) => say(format!("Fast-forwarded {}: #{} -> {}", name, from, shown)),
+            None => say(format!("New branch {} at {}", name, shown)),
+        }
+        moved.push(json!({ "name": name, "from": from, "to": to, "hash": hash }));
+        scm_data.branches.insert(name, hash);
+    }
+    let tags = take_tags(&mut scm_data, &info.tags, spec);
+    for name in &tags {
+        say(format!("New tag {}", name));
+    }
+    report(&json!({ "fetched": count, "branches": moved, "tags": tags }));
+    if moves_checkout {
+        let tip = head_index(&scm_data);
+        check_out_tree(&mut scm_data, tip, false)?;
+    }
+    save_scm(&scm_data)?;
+    if count == 0 {
+        say(format!("Already have every commit from {}", spec));
+    } else {
+        say(format!("Fetched {} commits from {}", count, spec));
+    }
+    Ok(())
+}
+
+/// Send the commits another repository lacks and fast-forward its copy of a branch, refusing
+/// when that would drop commits it has
+fn push(args: &[String]) -> Result<(), ScmError> {
+    let (spec, branch) = match args {
+        [spec] => (spec, None),
+        [spec, branch] => (spec, Some(branch)),
+        _ => return Err(ScmError::Usage("Usage: scm push <path | http://host:port> [<branch>]".to_string())),
+    };
+    require_repo()?;
+    let remote = Remote::open(spec)?;
+    let scm_data = load_scm()?;
+    let name = branch.cloned().unwrap_or_else(|| scm_data.branch.clone());
+    let tip = branch_index(&scm_data, &name).ok_or_else(|| ScmError::MissingCommit(format!("No branch named '{}'", name)))?;
+    let tip_hash = scm_data.commits[tip].hash.clone();
+    let pushed = remote.push(&scm_data, &name, &tip_hash)?;
+    report(&json!({ "pushed": pushed.unwrap_or(0), "branch": name, "index": tip, "hash": tip_hash }));
+    match pushed {
+        Some(count) => say(format!("Pushed {} commits to {} ({} at {}...)", count, spec, name, short(&tip_hash))),
+        None => say(format!("{} is up to date on {}", name, spec)),
+    }
+    Ok(())
+}
+
+/// Serve the repository to `scm clone`, `pull` and `push` over HTTP on the loopback interface,
+/// one request at a time
+fn serve(args: &[String]) -> Result<(), ScmError> {
+    let port = match args {
+        [] => 7878,
+        [flag, port] if flag == "--port" => {
+            port.parse().map_err(|_| ScmError::Usage(format!("Invalid port '{}'", port)))?
+        }
+        _ => return Err(ScmError::Usage("Usage: scm serve [--port <n>]".to_string())),
+    };
+    require_repo()?;
+    load_scm()?;
+    let listener = TcpListener::bind(("127.0.0.1", port)).map_err(io_error(format!("port {}", port)))?;
+    let address = listener.local_addr().map_err(io_error("listening socket"))?;
+    let dir = env::current_dir().unwrap_or_default();
+    report(&json!({ "address": format!("http://{}", address), "directory": dir }));
+    say(format!("Serving {} on http://{}", dir.display(), address));
+    // Clients need the address while the server runs, not when it stops
+    print_report();
+    io::stdout().flush().ok();
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+        // Requests are answered one at a time, so an idle client must not hold up the rest
+        stream.set_read_timeout(Some(NETWORK_TIMEOUT)).ok();
+        stream.set_write_timeout(Some(NETWORK_TIMEOUT)).ok();
+        let (status, body) = match read_http_request(&mut stream) {
+            Ok((method, path, body)) => match handle_request(&method, &path, &body) {
+                Ok(reply) => ("200 OK", reply),
+                Err(e) => {
+                    eprintln!("{} {}: {}", method, path, e);
+                    ("400 Bad Request", to_json(&ErrorReply { error: e.to_string(), code: e.exit_code() }))
+                }
+            },
+            Err(e) => ("400 Bad Request", to_json(&ErrorReply { error: e.to_string(), code: e.exit_code() })),
+        };
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        );
+        // A client that went away only loses its own reply
+        stream.write_all(head.as_bytes()).and_then(|_| stream.write_all(&body)).ok();
+    }
+    Ok(())
+}
+
+/// Method, path and body of an HTTP request
+fn read_http_request(stream: &mut TcpStream) -> Result<(String, String, Vec<u8>), ScmError> {
+    let mut reader = io::BufReader::new(stream);
+    let mut read_line = |line: &mut String| -> Result<(), ScmError> {
+        let read = (&mut reader).take(MAX_HEADER_LINE).read_line(line).map_err(io_error("request"))?;
+        if read as u64 == MAX_HEADER_LINE && !line.ends_with('\n') {
+            return Err(ScmError::Usage(format!("Request line longer than {} bytes", MAX_HEADER_LINE)));
+        }
+        Ok(())
+    };
+    let mut request_line = String::new();
+    read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(ScmError::Usage(format!("Malformed request line '{}'", request_line.trim())));
+    };
+    let mut length: u64 = 0;
+    loop {
+        let mut header = String::new();
+        read_line(&mut header)?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().map_err(|_| ScmError::Usage(format!("Invalid header '{}'", header)))?;
+            }
+        }
+    }
+    if length > MAX_REQUEST_BODY {
+        return Err(ScmError::Usage(format!("Request body of {} bytes is over the limit of {}", length, MAX_REQUEST_BODY)));
+    }
+    // The body grows as it arrives rather than trusting the length up front
+    let mut body = Vec::new();
+    reader.take(length).read_to_end(&mut body).map_err(io_error("request body"))?;
+    if (body.len() as u64) < length {
+        return Err(ScmError::Usage("Request body ended early".to_string()));
+    }
+    Ok((method.to_string(), path.to_string(), body))
+}
+
+/// Answer one request to `scm serve`
+fn handle_request(method: &str, path: &str, body: &[u8]) -> Result<Vec<u8>, ScmError> {
+    let number = |text: &str| text.parse::<usize>().map_err(|_| ScmError::Usage(format!("Invalid number '{}'", text)));
+    let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, parts.as_slice()) {
+        ("GET", ["info"]) => Ok(to_json(&remote_info(&load_scm()?))),
+        // Nodes `from..to` of one level of the Merkle tree, leaves being level 0
+        ("GET", ["merkle", level, from, to]) => {
+            let scm_data = load_scm()?;
+            let level = scm_data.merkle.get(number(level)?).map(Vec::as_slice).unwrap_or_default();
+            let from = number(from)?.min(level.len());
+            let to = number(to)?.clamp(from, level.len());
+            Ok(to_json(&level[from..to]))
+        }
+        ("GET", ["commits", from]) => {
+            let scm_data = load_scm()?;
+            let from = number(from)?.min(scm_data.commits.len());
+            let commits: Vec<CommitRef> = (from..scm_data.commits.len())
+                .map(|idx| CommitRef { hash: scm_data.commits[idx].hash.clone(), id: scm_data.ids[idx].clone() })
+                .collect();
+            Ok(to_json(&commits))
+        }
+        ("POST", ["objects"]) => {
+            let ids: Vec<String> = parse_json(body, "object request")?;
+            let mut objects = HashMap::new();
+            for id in ids {
+                if !is_object_id(&id) {
+                    return Err(ScmError::Usage(format!("Invalid object id '{}'", id)));
+                }
+                objects.insert(id.clone(), hex::encode(read_object(&id)?));
+            }
+            Ok(to_json(&objects))
+        }
+        ("POST", ["push"]) => {
+            let request: PushRequest = parse_json(body, "push request")?;
+            let mut objects = HashMap::new();
+            for (id, bytes) in &request.objects {
+                let bytes = hex::decode(bytes).map_err(|e| ScmError::Parse(format!("object {}: {}", id, e)))?;
+                objects.insert(id.clone(), bytes);
+            }
+            let count = accept_push(&request.branch, &request.tip, &request.tags, &mut |scm_data| {
+                let mut lookup = |ids: &[String]| Ok(ids.iter().filter_map(|id| objects.get(id).map(|b| (id.clone(), b.clone()))).collect());
+                receive_commits("the pushing repository", &request.commits, &mut lookup, scm_data)
+            })?;
+            Ok(to_json(&count))
+        }
+        _ => Err(ScmError::Usage(format!("Unknown request {} {}", method, path))),
+    }
+}
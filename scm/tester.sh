@@ -0,0 +1,674 @@
+cargo build --release 2>/dev/null
+SCM="$(pwd)/target/release/scm"
+WORK="$(mktemp -d)"
+SNAPS="$(mktemp -d)"
+N=20
+unset EDITOR SCM_AUTHOR SCM_DATE
+
+# Randomly insert, delete or replace a few lines of a file
+mutate() {
+    awk -v seed="$RANDOM" '
+        BEGIN { srand(seed) }
+        { lines[NR] = $0 }
+        END {
+            n = NR
+            for (k = 0; k < 1 + int(rand() * 4); k++) {
+                op = int(rand() * 3); at = 1 + int(rand() * (n + 1))
+                if (op == 0 || n == 0) {
+                    for (m = n; m >= at; m--) lines[m + 1] = lines[m]
+                    lines[at] = "line " int(rand() * 10); n++
+                } else if (at <= n && op == 1) {
+                    for (m = at; m < n; m++) lines[m] = lines[m + 1]
+                    n--
+                } else if (at <= n) {
+                    lines[at] = "line " int(rand() * 10)
+                }
+            }
+            for (m = 1; m <= n; m++) print lines[m]
+        }' "$1" > "$1.tmp" && mv "$1.tmp" "$1"
+}
+
+# Tracked files in the working tree, excluding the repository itself
+files() {
+    find . -path ./.scm -prune -o -type f -print | sed 's|^\./||' | grep -v '^a\.txt$'
+}
+
+cd "$WORK"
+mkdir -p src
+for f in a.txt b.txt src/c.txt; do seq 1 8 | sed 's/^/line /' > "$f"; done
+
+echo " === Committing $N random states. === "
+for i in $(seq 0 $((N - 1))); do
+    if [ "$i" -gt 0 ]; then
+        for f in a.txt $(files); do [ $((RANDOM % 2)) -eq 0 ] && mutate "$f"; done
+        [ $((RANDOM % 4)) -eq 0 ] && mkdir -p src && seq 0 $((RANDOM % 6)) | sed 's/^/new /' > "src/n$i.txt"
+        f=$(files | shuf -n1)
+        case $((RANDOM % 5)) in
+            0) [ -n "$f" ] && rm "$f" ;;
+            1) [ -n "$f" ] && mv "$f" "m$i.txt" ;;
+        esac
+        find . -mindepth 1 -type d -empty ! -path './.scm*' -delete
+    fi
+    echo "state $i" >> a.txt
+    "$SCM" commit -a > /dev/null
+    cp -r . "$SNAPS/$i" && rm -r "$SNAPS/$i"/.scm*
+done
+# Fsck replays every commit's diffs and compares them with its tree
+"$SCM" fsck > /dev/null || echo "Fsck found problems in the random history"
+
+echo " === Finding errors checking out each commit. === "
+for i in $(seq 0 $((N - 1)) | shuf); do
+    "$SCM" checkout "#$i" > /dev/null
+    diff -r --exclude='.scm*' "$SNAPS/$i" . || echo "Mismatch after checking out commit #$i"
+done
+echo "dirty" >> a.txt
+"$SCM" checkout "#0" 2> /dev/null && echo "Checkout overwrote uncommitted changes"
+"$SCM" checkout "#$((N - 1))" --force > /dev/null
+diff -r --exclude='.scm*' "$SNAPS/$((N - 1))" . || echo "Mismatch after forced checkout"
+
+echo " === Finding errors replaying diffs without tree snapshots. === "
+cp -r . "$SNAPS/treeless" && (
+    cd "$SNAPS/treeless"
+    for tree in $(cut -d' ' -f3 .scm/commits); do rm ".scm/objects/${tree:0:2}/${tree:2}"; done
+    for i in $(seq 0 $((N - 1)) | shuf); do
+        "$SCM" checkout "#$i" > /dev/null
+        diff -r --exclude='.scm*' "$SNAPS/$i" . || echo "Mismatch after replaying diffs to commit #$i"
+    done
+)
+
+echo " === Finding errors reverting through history. === "
+for i in $(seq $((N - 2)) -1 0); do
+    "$SCM" revert > /dev/null
+    diff -r --exclude='.scm*' "$SNAPS/$i" . || echo "Mismatch after reverting to commit #$i"
+done
+
+echo " === Finding errors in rename and deletion tracking. === "
+mv a.txt renamed.txt
+"$SCM" commit -a > /dev/null
+"$SCM" log | grep "Renamed files: 1" > /dev/null || echo "Rename of a.txt was not detected"
+rm renamed.txt
+"$SCM" commit -a > /dev/null
+"$SCM" log | grep "Deleted files: 1" > /dev/null || echo "Deletion of renamed.txt was not recorded"
+"$SCM" checkout "#0" > /dev/null
+diff -r --exclude='.scm*' "$SNAPS/0" . || echo "Mismatch after checking out commit #0 again"
+
+echo " === Finding errors in branches. === "
+"$SCM" branch exp "#3" > /dev/null
+"$SCM" switch exp > /dev/null
+diff -r --exclude='.scm*' "$SNAPS/3" . || echo "Mismatch after switching to a branch at commit #3"
+echo "experiment" > exp.txt
+"$SCM" commit -a > /dev/null
+cp -r . "$SNAPS/exp" && rm -r "$SNAPS/exp"/.scm*
+"$SCM" switch main > /dev/null
+[ -e exp.txt ] && echo "Branch file leaked into main"
+"$SCM" switch exp > /dev/null
+diff -r --exclude='.scm*' "$SNAPS/exp" . || echo "Mismatch after switching back to branch exp"
+"$SCM" log | grep "Parent: #3" > /dev/null || echo "Branch commit does not record commit #3 as its parent"
+echo "notes" > notes.txt
+"$SCM" switch main > /dev/null || echo "An untracked file blocked switching branches"
+[ "$(cat notes.txt)" = "notes" ] || echo "Switching branches changed an untracked file"
+echo "other" > exp.txt
+"$SCM" switch exp > /dev/null 2>&1
+[ $? -eq 6 ] || echo "Switching over an untracked file the branch has did not exit with 6"
+[ "$(cat exp.txt)" = "other" ] || echo "Switching branches overwrote an untracked file"
+rm exp.txt notes.txt && "$SCM" switch exp > /dev/null
+
+echo " === Finding errors in three-way merges. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+seq 1 10 > f.txt && echo "gone" > g.txt
+"$SCM" commit -a > /dev/null
+"$SCM" branch side > /dev/null
+sed -i 's/^2$/two/' f.txt && echo "ours" > o.txt
+"$SCM" commit -a > /dev/null
+"$SCM" switch side > /dev/null
+sed -i 's/^9$/nine/' f.txt && rm g.txt
+"$SCM" commit -a > /dev/null
+"$SCM" switch main > /dev/null
+echo "scratch" > scratch.txt
+"$SCM" merge side > /dev/null || echo "Non-overlapping merge failed"
+[ "$(cat scratch.txt)" = "scratch" ] && rm scratch.txt || echo "Merging lost an untracked file"
+[ "$(cat f.txt | tr '\n' ' ')" = "1 two 3 4 5 6 7 8 nine 10 " ] || echo "Merged f.txt is wrong"
+[ -e g.txt ] && echo "File deleted on the merged branch survived"
+[ -e o.txt ] || echo "File added on the current branch was lost"
+"$SCM" log | grep "Parents: #1, #2" > /dev/null || echo "Merge commit does not record two parents"
+"$SCM" branch clash "#1" > /dev/null
+sed -i 's/^5$/five-main/' f.txt && "$SCM" commit -a > /dev/null
+cp f.txt "$SNAPS/before-merge"
+"$SCM" switch clash > /dev/null
+sed -i 's/^5$/five-clash/' f.txt && "$SCM" commit -a > /dev/null
+"$SCM" switch main > /dev/null
+"$SCM" merge clash 2> /dev/null && echo "Overlapping edits merged without a conflict"
+grep -c '^<<<<<<< main$\|^=======$\|^>>>>>>> clash$' f.txt | grep -qx 3 || echo "Conflict markers missing"
+"$SCM" merge --abort > /dev/null
+diff "$SNAPS/before-merge" f.txt || echo "Mismatch after aborting a merge"
+"$SCM" switch clash > /dev/null && echo side > added_on_side.txt && "$SCM" commit -a > /dev/null
+"$SCM" switch main > /dev/null
+"$SCM" merge clash > /dev/null 2>&1
+[ -e added_on_side.txt ] || echo "Conflicting merge did not bring in the other side's new file"
+"$SCM" merge --abort > /dev/null
+[ -e added_on_side.txt ] && echo "Aborting a merge left a file only the other side had"
+"$SCM" status > /dev/null || echo "Tree was not clean after aborting a merge"
+"$SCM" branch gone > /dev/null && echo "ours edited" > o.txt && "$SCM" commit -a > /dev/null
+"$SCM" switch gone > /dev/null && rm o.txt && "$SCM" commit -a > /dev/null
+"$SCM" switch main > /dev/null
+"$SCM" merge gone > /dev/null 2>&1
+[ $? -eq 1 ] || echo "Merging a deletion of an edited file did not conflict"
+"$SCM" status | grep "^  o.txt$" > /dev/null || echo "Status did not list an unresolved conflict"
+"$SCM" commit -a -m "too soon" > /dev/null 2>&1 && echo "Commit concluded a merge with an unstaged conflict"
+"$SCM" add o.txt > /dev/null && "$SCM" commit -a -m "resolved" > /dev/null || echo "Commit of a resolved conflict failed"
+"$SCM" log | grep "Parents: " > /dev/null || echo "Resolved merge was not recorded as a merge"
+
+echo " === Finding errors in commit messages, authors and dates. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+echo one > f.txt
+SCM_AUTHOR=alice SCM_DATE=2024-01-15 "$SCM" commit -a -m "first commit" > /dev/null
+echo two >> f.txt
+"$SCM" config author 2> /dev/null; [ $? = 1 ] || echo "Looking up an unset config key did not exit 1"
+"$SCM" --json config author | grep '"ok": *false' > /dev/null || echo "Unset config key was not a JSON error"
+"$SCM" config author bob
+[ "$("$SCM" config author)" = bob ] || echo "Config lookup did not print the value"
+"$SCM" --json config author | grep '"value": *"bob"' > /dev/null || echo "Config lookup missing from JSON"
+SCM_DATE="2024-03-01 12:00" "$SCM" commit -a -m "second commit" > /dev/null
+echo three >> f.txt
+EDITOR="sed -i 1s/^/edited/" SCM_DATE=2024-06-30 "$SCM" commit -a > /dev/null
+"$SCM" log | grep "^    first commit$" > /dev/null || echo "Commit message missing from log"
+"$SCM" log | grep "^Date: 2024-03-01 12:00:00 UTC$" > /dev/null || echo "Commit date missing from log"
+"$SCM" log | grep "^    edited$" > /dev/null || echo "Message written in \$EDITOR was not recorded"
+[ "$("$SCM" log --author alice | grep -c '^Commit #')" = 1 ] || echo "Filtering log by author failed"
+[ "$("$SCM" log --author bob | grep -c '^Commit #')" = 2 ] || echo "Author from config was not used"
+[ "$("$SCM" log --since 2024-02-01 --until 2024-03-01 | grep -c '^Commit #')" = 1 ] || echo "Filtering log by date failed"
+echo four >> f.txt
+H1=$(SCM_DATE=2024-07-01 "$SCM" commit -a -m "one message" | sed 's/.*hash: \(.*\)\.\.\.)/\1/')
+"$SCM" revert > /dev/null && echo four >> f.txt
+H2=$(SCM_DATE=2024-07-01 "$SCM" commit -a -m "another message" | sed 's/.*hash: \(.*\)\.\.\.)/\1/')
+[ "$H1" != "$H2" ] || echo "Commit message is not part of the commit hash"
+
+echo " === Finding errors in status. === "
+"$SCM" status > /dev/null || echo "Status reported a clean tree as dirty"
+echo more >> f.txt && echo new > g.txt
+"$SCM" status > /dev/null && echo "Status reported a dirty tree as clean"
+[ "$("$SCM" status --short | tr '\n' ' ')" = " M f.txt ?? g.txt " ] || echo "Short status is wrong"
+"$SCM" commit -a > /dev/null && mv g.txt h.txt && rm f.txt
+[ "$("$SCM" status --short | tr '\n' ' ')" = " D f.txt  D g.txt ?? h.txt " ] || echo "Short status misses unstaged deletions"
+"$SCM" add .
+[ "$("$SCM" status --short | tr '\n' ' ')" = "D  f.txt R  g.txt -> h.txt " ] || echo "Short status misses staged deletions or renames"
+
+echo " === Finding errors in diff. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+mkdir -p src && seq 1 30 > src/f.txt && echo keep > g.txt
+"$SCM" commit -a > /dev/null
+cp src/f.txt "$SNAPS/diff-old"
+mutate src/f.txt && mutate src/f.txt && printf 'last' >> src/f.txt && echo changed > g.txt
+cp "$SNAPS/diff-old" "$SNAPS/diff-patched"
+"$SCM" diff -u src | tail -n +2 | patch -s "$SNAPS/diff-patched" > /dev/null
+diff "$SNAPS/diff-patched" src/f.txt > /dev/null || echo "Unified diff does not reproduce the working tree"
+cp "$SNAPS/diff-old" "$SNAPS/diff-patched"
+"$SCM" diff src/f.txt | tail -n +2 | patch -s "$SNAPS/diff-patched" > /dev/null
+diff "$SNAPS/diff-patched" src/f.txt > /dev/null || echo "Normal diff does not reproduce the working tree"
+"$SCM" diff g.txt | grep -c '^diff ' | grep -qx 1 || echo "Path filter did not select a single file"
+"$SCM" commit -a > /dev/null
+[ -z "$("$SCM" diff)" ] || echo "Diff of a clean tree is not empty"
+"$SCM" diff '#0' '#1' -- g.txt | grep '^> changed$' > /dev/null || echo "Diff between two commits is wrong"
+"$SCM" diff --color '#0' | grep "$(printf '\033')\[32m" > /dev/null || echo "Colored diff has no color"
+for c in 0 1 2 3 4 5 6 7 8 9 a b c d e f; do echo "$c" > "$c"; done && "$SCM" commit -a > /dev/null
+for c in 0 1 2 3 4 5 6 7 8 9 a b c d e f; do echo changed >> "$c"; done
+for c in 0 1 2 3 4 5 6 7 8 9 a b c d e f; do
+    [ "$("$SCM" diff "$c" | grep -c '^diff ')" = 1 ] || echo "Diff took one-character path $c for a commit"
+done
+"$SCM" commit -a > /dev/null
+PREFIX=$("$SCM" status | grep "^Commit hash" | cut -c 14-21)
+echo file > "$PREFIX"
+"$SCM" diff "$PREFIX" > /dev/null 2>&1
+[ $? -eq 2 ] || echo "Diff of a name that is both a commit and a path did not exit with 2"
+"$SCM" diff "$PREFIX" -- > /dev/null || echo "Diff did not take a commit before '--'"
+rm "$PREFIX"
+
+echo " === Finding errors in the staging area. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+seq 1 20 > f.txt && echo a > a.txt
+"$SCM" commit > /dev/null
+echo b > b.txt && sed -i 's/^2$/two/' f.txt
+"$SCM" commit | grep "No changes staged" > /dev/null || echo "Commit without staged changes did not refuse"
+"$SCM" add b.txt && "$SCM" commit -m "add b" > /dev/null
+[ "$("$SCM" status --short | tr '\n' ' ')" = " M f.txt " ] || echo "Commit took unstaged changes"
+"$SCM" add f.txt && "$SCM" reset f.txt
+[ "$("$SCM" status --short | tr '\n' ' ')" = " M f.txt " ] || echo "Reset did not unstage a file"
+sed -i 's/^19$/nineteen/' f.txt
+printf 'y\nn\n' | "$SCM" add -p f.txt > /dev/null
+"$SCM" diff --staged | grep '^> two$' > /dev/null || echo "Chosen hunk was not staged"
+"$SCM" diff --staged | grep 'nineteen' > /dev/null && echo "Skipped hunk was staged"
+"$SCM" commit -m "partial" > /dev/null
+[ "$("$SCM" diff | grep '^[<>]' | tr '\n' ' ')" = "< 19 > nineteen " ] || echo "Partial commit left the wrong changes"
+"$SCM" add . && "$SCM" commit > /dev/null
+[ "$(cat f.txt | tr '\n' ' ')" = "$(seq 1 20 | sed 's/^2$/two/; s/^19$/nineteen/' | tr '\n' ' ')" ] || echo "Working tree changed by staging"
+seq 1 10 > g.txt && "$SCM" add g.txt && "$SCM" commit -m "g" > /dev/null
+seq 3 10 | sed 's/^4$/4x/' > g.txt
+OUT=$(printf 'n\ny\n' | "$SCM" add -p g.txt 2>&1) || echo "Add -p failed on changes close together"
+echo "$OUT" | grep "@@ -3,5 +1,5 @@$" > /dev/null || echo "Add -p hunk header was wrong for changes close together"
+[ "$("$SCM" diff --staged | grep '^[<>]' | tr '\n' ' ')" = "< 4 > 4x " ] || echo "Add -p staged the wrong one of two close changes"
+"$SCM" add g.txt && "$SCM" commit -m "g2" > /dev/null
+
+echo " === Finding errors in Merkle proofs. === "
+ROOT=$("$SCM" log | sed -n 's/^Merkle Root: //p')
+for k in 0 1 2 3; do
+    "$SCM" prove "#$k" > "$SNAPS/proof.json"
+    "$SCM" verify-proof "$ROOT" "$SNAPS/proof.json" > /dev/null || echo "Proof of commit #$k did not verify"
+done
+sed -i '0,/"sibling": "[0-9a-f]/s/"sibling": "[0-9a-f]/"sibling": "x/' "$SNAPS/proof.json"
+"$SCM" verify-proof "$ROOT" "$SNAPS/proof.json" 2> /dev/null && echo "Tampered proof verified"
+"$SCM" prove "#1" | sed 's/"index": 1,/"index": 7,/' > "$SNAPS/proof.json"
+"$SCM" verify-proof "$ROOT" "$SNAPS/proof.json" 2> /dev/null && echo "Proof with a changed index verified"
+"$SCM" prove "#1" | sed 's/"commits": [0-9]*,/"commits": 1000,/' > "$SNAPS/proof.json"
+"$SCM" verify-proof "$ROOT" "$SNAPS/proof.json" 2> /dev/null && echo "Proof with a changed commit count verified"
+mkdir "$SNAPS/four" && (
+    cd "$SNAPS/four"
+    for k in 0 1 2 3; do echo "$k" > f.txt && "$SCM" commit -a > /dev/null; done
+    ROOT=$("$SCM" log | sed -n 's/^Merkle Root: //p')
+    # With 4 commits, the last sibling of #2 is the node over #0 and #1, and that of #0 the one over #2 and #3
+    INNER=$("$SCM" prove "#2" | grep -o '"sibling": "[0-9a-f]*"' | tail -1 | cut -d'"' -f4)
+    UPPER=$("$SCM" prove "#0" | grep -o '"sibling": "[0-9a-f]*"' | tail -1 | cut -d'"' -f4)
+    printf '{"commit":"%s","index":0,"commits":2,"root":"%s","path":[{"side":"right","sibling":"%s"}]}' \
+        "$INNER" "$ROOT" "$UPPER" > proof.json
+    "$SCM" verify-proof "$ROOT" proof.json > /dev/null 2>&1 && echo "Proof passing off an inner node as a commit verified"
+)
+
+echo " === Finding errors in fsck. === "
+"$SCM" fsck > /dev/null 2>&1 || echo "Fsck reported problems in a healthy repository"
+ID=$(sed -n 2p .scm/commits | cut -d' ' -f1)
+sed -i 's/"message":"add b"/"message":"forged"/' ".scm/objects/${ID:0:2}/${ID:2}"
+"$SCM" fsck 2>&1 | grep "^commit #1 " > /dev/null || echo "Fsck did not locate a tampered commit"
+"$SCM" fsck > /dev/null 2>&1 && echo "Fsck exited cleanly despite a tampered commit"
+
+echo " === Finding errors in .scmignore handling. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+mkdir -p target/debug src/gen docs/build lib/build
+printf '# build output\ntarget/\n*.log\n!keep.log\n/top.tmp\ndocs/**/*.o\n' > .scmignore
+printf 'gen/\n[ab].txt\n' > src/.scmignore
+for f in main.rs target/debug/out top.tmp lib/top.tmp a.log keep.log src/x.log src/a.txt src/c.txt \
+         src/gen/g.rs docs/build/x.o docs/y.o lib/build/z.o a.txt; do echo "$f" > "$f"; done
+"$SCM" commit -a > /dev/null
+"$SCM" status | grep "Tracked files: 6$" > /dev/null || echo "Ignore patterns selected the wrong files"
+rm -r src/c.txt && echo changed > target/debug/out && echo changed > src/b.txt
+"$SCM" commit -a > /dev/null
+"$SCM" checkout "#0" > /dev/null || echo "Changes to ignored files blocked a checkout"
+[ -e src/c.txt ] && [ "$(cat target/debug/out)" = changed ] || echo "Checkout touched the wrong files"
+
+echo " === Finding errors restoring binary files and line endings byte-exact. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+printf 'one\r\ntwo\r\n' > crlf.txt && printf 'no newline' > eol.txt && printf '\n\n' > blank.txt
+head -c 300 /dev/urandom > blob.bin && printf 'a\0b\n' > nul.txt
+"$SCM" commit -a > /dev/null
+cp -r . "$SNAPS/bin0" && rm -r "$SNAPS/bin0"/.scm*
+printf 'one\r\nTWO\r\nthree' > crlf.txt && printf 'no newline\n' > eol.txt
+head -c 200 /dev/urandom >> blob.bin && printf '\377\376' > latin.txt
+"$SCM" commit -a > /dev/null
+cp -r . "$SNAPS/bin1" && rm -r "$SNAPS/bin1"/.scm*
+"$SCM" checkout "#0" > /dev/null
+diff -r --exclude='.scm*' "$SNAPS/bin0" . > /dev/null || echo "Mismatch after checking out binary and CRLF files"
+"$SCM" checkout "#1" > /dev/null
+diff -r --exclude='.scm*' "$SNAPS/bin1" . > /dev/null || echo "Mismatch after checking out modified binary and CRLF files"
+"$SCM" revert > /dev/null
+diff -r --exclude='.scm*' "$SNAPS/bin0" . > /dev/null || echo "Mismatch after reverting binary and CRLF changes"
+ID=$(sed -n 2p .scm/commits | cut -d' ' -f1)
+grep 'u0000binary' ".scm/objects/${ID:0:2}/${ID:2}" > /dev/null && echo "Commit of a modified binary file holds its contents"
+"$SCM" fsck > /dev/null || echo "Fsck found problems with binary changes"
+
+echo " === Finding errors in tags. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+echo one > f.txt && "$SCM" commit -a > /dev/null
+echo two > f.txt && "$SCM" commit -a > /dev/null
+"$SCM" tag v0.1 "#0" > /dev/null || echo "Creating a lightweight tag failed"
+SCM_AUTHOR=releaser SCM_DATE=2026-01-02 "$SCM" tag v1.0 -m "First release" > /dev/null || echo "Creating an annotated tag failed"
+"$SCM" tag | grep "^v1.0 #1 .* First release$" > /dev/null || echo "Tag list does not show the annotated tag"
+"$SCM" tag | grep "^v0.1 #0 " > /dev/null || echo "Tag list does not show the lightweight tag"
+grep '"tagger": "releaser"' .scm/state > /dev/null || echo "Annotated tag did not record its tagger"
+"$SCM" log | grep "^Commit #0 (tag: v0.1)$" > /dev/null || echo "Log does not decorate commits with tags"
+"$SCM" diff v0.1 v1.0 | grep "^> two$" > /dev/null || echo "Diff does not accept tags"
+"$SCM" checkout v0.1 > /dev/null && [ "$(cat f.txt)" = one ] || echo "Checkout does not accept tags"
+"$SCM" switch main > /dev/null
+"$SCM" tag v1.0 > /dev/null 2>&1 && echo "A tag was created twice"
+"$SCM" tag main > /dev/null 2>&1 && echo "A tag was created with a branch's name"
+"$SCM" branch v0.1 > /dev/null 2>&1 && echo "A branch was created with a tag's name"
+"$SCM" tag -d v0.1 > /dev/null && ! "$SCM" tag | grep v0.1 > /dev/null || echo "Deleting a tag failed"
+"$SCM" fsck > /dev/null 2>&1 || echo "Fsck found problems in a tagged repository"
+cd .. && "$SCM" clone "$WORK" "$SNAPS/tagclone" > /dev/null && cd "$SNAPS/tagclone"
+"$SCM" tag | grep "^v1.0 " > /dev/null || echo "Clone did not bring tags"
+"$SCM" tag v2.0 > /dev/null && "$SCM" push "$WORK" > /dev/null
+(cd "$WORK" && "$SCM" tag | grep "^v2.0 " > /dev/null) || echo "Push did not send new tags"
+cd "$WORK"
+
+echo " === Finding errors in error reporting and exit codes. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+echo one > f.txt && "$SCM" commit -a > /dev/null
+echo two > f.txt && "$SCM" commit -a > /dev/null
+"$SCM" frobnicate > /dev/null 2>&1; [ $? -eq 2 ] || echo "Unknown command did not exit with 2"
+"$SCM" checkout nosuchref > /dev/null 2>&1; [ $? -eq 5 ] || echo "Unknown commit did not exit with 5"
+echo three > f.txt
+"$SCM" checkout "#0" > /dev/null 2>&1; [ $? -eq 6 ] || echo "Checkout over a dirty tree did not exit with 6"
+[ "$(cat f.txt)" = three ] || echo "Refused checkout changed the working tree"
+echo two > f.txt
+cp .scm/state "$SNAPS/state"
+head -c 20 "$SNAPS/state" > .scm/state
+OUT=$("$SCM" log 2>&1); CODE=$?
+[ $CODE -eq 4 ] || echo "Truncated state did not exit with 4"
+echo "$OUT" | grep panicked > /dev/null && echo "Truncated state made scm panic"
+cp "$SNAPS/state" .scm/state
+rm -r .scm/objects/*/
+OUT=$("$SCM" status 2>&1); CODE=$?
+[ $CODE -eq 7 ] || echo "Missing objects did not exit with 7"
+echo "$OUT" | grep panicked > /dev/null && echo "Missing objects made scm panic"
+: > .scm/commits
+"$SCM" log > /dev/null 2>&1; [ $? -eq 7 ] || echo "Empty commit list did not exit with 7"
+
+echo " === Finding errors in locking and crash safety. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+echo one > f.txt && "$SCM" commit -a > /dev/null
+cp .scm/state "$SNAPS/state"
+echo two > f.txt && "$SCM" commit -a > /dev/null
+cmp -s .scm.bak/state "$SNAPS/state" || echo "Backup does not hold the state from before the last commit"
+[ -e .scm/lock ] && echo "Lock file left behind after a commit"
+find .scm -name '*.tmp' | grep . > /dev/null && echo "Temporary files left behind after a commit"
+echo three > f.txt
+"$SCM" checkout "#0" > /dev/null 2>&1
+[ -e .scm/lock ] && echo "Lock file left behind after a refused checkout"
+echo 12345 > .scm/lock
+OUT=$("$SCM" commit -a 2>&1); CODE=$?
+[ $CODE -eq 8 ] || echo "Commit into a locked repository did not exit with 8"
+echo "$OUT" | grep "locked" > /dev/null || echo "Commit into a locked repository did not say so"
+"$SCM" log > /dev/null 2>&1 || echo "Log refused to read a locked repository"
+[ "$(cat .scm/lock)" = 12345 ] || echo "A refused command removed another command's lock"
+rm .scm/lock
+"$SCM" commit -a > /dev/null || echo "Commit failed after the lock was removed"
+for i in 1 2 3 4 5 6 7 8; do
+    "$SCM" branch "b$i" > "$SNAPS/branch$i" 2>&1 &
+done
+wait
+for i in 1 2 3 4 5 6 7 8; do
+    if grep "Created" "$SNAPS/branch$i" > /dev/null; then
+        "$SCM" branch | grep " b$i " > /dev/null || echo "Concurrent branch creation lost branch b$i"
+    else
+        grep "locked" "$SNAPS/branch$i" > /dev/null || echo "Concurrent branch b$i failed for another reason"
+    fi
+done
+"$SCM" fsck > /dev/null 2>&1 || echo "Fsck found problems after concurrent commands"
+cp .scm/commits "$SNAPS/commits"
+echo four > f.txt && "$SCM" commit -a > /dev/null
+[ "$(cat .scm.bak/commits.length)" = "$(wc -c < "$SNAPS/commits")" ] || echo "Backup does not hold the commit list length from before the last commit"
+[ "$(head -n -1 .scm/commits)" = "$(cat "$SNAPS/commits")" ] || echo "Commit did not just append to the commit list"
+printf '%s' "partial line" >> .scm/commits
+"$SCM" log > /dev/null 2>&1 || echo "A partial line at the end of the commit list broke loading"
+echo five > f.txt && "$SCM" commit -a > /dev/null
+grep "partial" .scm/commits > /dev/null && echo "Commit did not replace a partial line in the commit list"
+"$SCM" fsck > /dev/null 2>&1 || echo "Fsck found problems after replacing a partial commit list line"
+cut -d' ' -f1 .scm/commits > "$SNAPS/ids" && cp "$SNAPS/ids" .scm/commits
+"$SCM" log > /dev/null 2>&1 || echo "A commit list of bare object ids did not load"
+echo six > f.txt && "$SCM" commit -a > /dev/null
+[ "$(awk '{ print NF }' .scm/commits | sort -u | head -1)" -ge 3 ] || echo "Commit did not rewrite an older commit list with summaries"
+ID=$(sed -n 2p .scm/commits | cut -d' ' -f1)
+mv ".scm/objects/${ID:0:2}/${ID:2}" "$SNAPS/object"
+echo seven > f.txt && "$SCM" commit -a > /dev/null 2>&1 || echo "Commit read commit objects of older history"
+"$SCM" log > /dev/null 2>&1; [ $? -eq 7 ] || echo "Log did not notice a missing commit object"
+mv "$SNAPS/object" ".scm/objects/${ID:0:2}/${ID:2}"
+
+echo " === Finding errors in clone, pull and push. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+mkdir origin && cd origin
+echo one > f.txt && "$SCM" commit -a > /dev/null
+echo two >> f.txt && mkdir d && echo deep > d/g.txt && "$SCM" commit -a > /dev/null
+cd ..
+"$SCM" clone origin copy > /dev/null || echo "Clone failed"
+diff -r --exclude='.scm*' origin copy > /dev/null || echo "Clone did not check out the same files"
+[ "$(cd origin && "$SCM" prove "#1" | grep root)" = "$(cd copy && "$SCM" prove "#1" | grep root)" ] || echo "Clone has a different Merkle root"
+(cd copy && "$SCM" fsck > /dev/null 2>&1) || echo "Fsck found problems in a clone"
+"$SCM" clone origin copy > /dev/null 2>&1 && echo "Clone into a non-empty directory succeeded"
+cd copy
+echo three >> f.txt && "$SCM" commit -a > /dev/null
+OUT=$("$SCM" push ../origin) || echo "Fast-forward push failed"
+echo "$OUT" | grep "Pushed 1 commits" > /dev/null || echo "Push did not send exactly the missing commit"
+cmp -s f.txt ../origin/f.txt || echo "Push did not update the checked-out branch of the other repository"
+(cd ../origin && "$SCM" status > /dev/null) || echo "Push left the other repository dirty"
+(cd ../origin && echo four >> f.txt && "$SCM" commit -a > /dev/null)
+echo other > h.txt && "$SCM" commit -a > /dev/null
+"$SCM" push ../origin > /dev/null 2>&1 && echo "Non-fast-forward push succeeded"
+[ "$(tail -1 ../origin/f.txt)" = four ] || echo "Rejected push changed the other repository"
+"$SCM" pull ../origin 2>&1 | grep "diverged" > /dev/null || echo "Pull did not report a diverged branch"
+THEIRS=$(cd ../origin && "$SCM" log | grep "^Hash" | head -1 | cut -d' ' -f2)
+"$SCM" merge "$THEIRS" > /dev/null || echo "Merging pulled commits failed"
+"$SCM" push ../origin > /dev/null || echo "Push after merging failed"
+diff -r --exclude='.scm*' . ../origin > /dev/null || echo "Mismatch after pushing a merge"
+cd ../origin
+echo five >> f.txt && "$SCM" commit -a > /dev/null && "$SCM" branch topic > /dev/null
+cd ../copy
+OUT=$("$SCM" pull ../origin) || echo "Fast-forward pull failed"
+echo "$OUT" | grep "Fetched 1 commits" > /dev/null || echo "Pull did not fetch exactly the missing commit"
+cmp -s f.txt ../origin/f.txt || echo "Pull did not update the working tree"
+"$SCM" branch | grep " topic " > /dev/null || echo "Pull did not bring a new branch"
+(cd ../origin && "$SCM" fsck > /dev/null 2>&1) || echo "Fsck found problems after pushing"
+"$SCM" fsck > /dev/null 2>&1 || echo "Fsck found problems after pulling"
+mkdir ../other && cd ../other && echo x > x.txt && "$SCM" commit -a > /dev/null
+"$SCM" pull ../origin > /dev/null 2>&1 && echo "Pull of unrelated history succeeded"
+cd ..
+
+echo " === Finding errors syncing with scm serve over loopback. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+mkdir served && cd served
+for i in 1 2 3 4 5 6 7; do
+    echo "$i" >> f.txt && "$SCM" commit -a > /dev/null
+done
+"$SCM" serve --port 0 > "$SNAPS/serve.out" 2> "$SNAPS/serve.err" &
+SERVER=$!
+for _ in $(seq 50); do
+    grep "http://" "$SNAPS/serve.out" > /dev/null 2>&1 && break
+    sleep 0.1
+done
+URL=$(grep -o "http://[0-9.:]*" "$SNAPS/serve.out")
+cd ..
+"$SCM" clone "$URL" client > /dev/null || echo "Clone over the network failed"
+diff -r --exclude='.scm*' served client > /dev/null || echo "Network clone did not check out the same files"
+[ "$(cd served && "$SCM" prove "#6" | grep root)" = "$(cd client && "$SCM" prove "#6" | grep root)" ] || echo "Network clone has a different Merkle root"
+cd client
+echo 8 >> f.txt && "$SCM" commit -a > /dev/null
+"$SCM" push "$URL" | grep "Pushed 1 commits" > /dev/null || echo "Network push did not send exactly the missing commit"
+cmp -s f.txt ../served/f.txt || echo "Network push did not update the served working tree"
+(cd ../served && echo 9 >> f.txt && "$SCM" commit -a > /dev/null)
+echo other > g.txt && "$SCM" commit -a > /dev/null
+"$SCM" push "$URL" > /dev/null 2>&1
+[ $? -eq 1 ] || echo "Non-fast-forward network push was not refused"
+"$SCM" pull "$URL" 2>&1 | grep "Fetched 1 commits" > /dev/null || echo "Network pull did not fetch exactly the missing commit"
+THEIRS=$(cd ../served && "$SCM" log | grep "^Hash" | head -1 | cut -d' ' -f2)
+"$SCM" merge "$THEIRS" > /dev/null || echo "Merging commits pulled over the network failed"
+"$SCM" push "$URL" > /dev/null || echo "Network push after merging failed"
+diff -r --exclude='.scm*' . ../served > /dev/null || echo "Mismatch after pushing a merge over the network"
+(cd ../served && "$SCM" fsck > /dev/null 2>&1) || echo "Fsck found problems after network pushes"
+"$SCM" pull "$URL" | grep "Already have every commit" > /dev/null || echo "Pull of an up-to-date repository fetched commits"
+ADDR=${URL#http://}
+exec 3<> "/dev/tcp/${ADDR%:*}/${ADDR##*:}"
+printf 'POST /push HTTP/1.1\r\nContent-Length: 99999999999999\r\n\r\n' >&3
+head -1 <&3 | grep "400 Bad Request" > /dev/null || echo "Serve did not refuse an oversized request body"
+exec 3<&-
+"$SCM" pull "$URL" > /dev/null 2>&1 || echo "Serve stopped answering after an oversized request"
+kill "$SERVER" 2> /dev/null
+wait "$SERVER" 2> /dev/null
+"$SCM" pull "$URL" > /dev/null 2>&1
+[ $? -eq 3 ] || echo "Pull from a stopped server did not exit with 3"
+cd ..
+
+echo " === Finding errors in signed commits. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+echo 1 > f.txt && "$SCM" init > /dev/null
+"$SCM" keygen "$SNAPS/key" > .scm/trusted-keys 2> /dev/null || echo "Keygen failed"
+"$SCM" keygen "$SNAPS/key" > /dev/null 2>&1
+[ $? -eq 1 ] || echo "Keygen replaced an existing key"
+"$SCM" config signing-key "$SNAPS/key" || echo "Setting the signing key failed"
+echo 2 >> f.txt && "$SCM" commit -a > /dev/null
+"$SCM" log | grep "^Signed-by" > /dev/null || echo "Commit made with a signing key was not signed"
+"$SCM" verify > /dev/null || echo "Verify rejected a commit signed by a trusted key"
+"$SCM" verify --require-signed > /dev/null 2>&1
+[ $? -eq 1 ] || echo "Verify --require-signed accepted an unsigned commit"
+"$SCM" fsck > /dev/null || echo "Fsck found problems in a signed commit"
+"$SCM" verify --keys /dev/null > /dev/null 2>&1
+[ $? -eq 1 ] || echo "Verify accepted a key that is not trusted"
+"$SCM" --json verify --keys /dev/null 2> /dev/null | grep '"messages":\[[^]]*untrusted key' > /dev/null || echo "Json verify did not report its problems"
+echo 3 >> f.txt && "$SCM" commit -a -m "legit" > /dev/null
+LEGIT=$(find .scm/objects -type f -exec grep -l '"message":"legit"' {} +)
+sed -i 's/"message":"legit"/"message":"EVIL"/' "$LEGIT"
+"$SCM" verify 2>&1 > /dev/null | grep "changed after it was made" > /dev/null || echo "Verify did not notice a signed commit's message was changed"
+"$SCM" verify > /dev/null 2>&1
+[ $? -eq 7 ] || echo "Verify did not exit with 7 on a changed commit"
+sed -i 's/"message":"EVIL"/"message":"legit"/' "$LEGIT"
+SIGNED=$(find .scm/objects -type f -exec grep -l '"sig":"' {} + | head -1)
+sed -i 's/"sig":"0/"sig":"x/; s/"sig":"[1-9a-f]/"sig":"0/; s/"sig":"x/"sig":"1/' "$SIGNED"
+"$SCM" verify > /dev/null 2>&1
+[ $? -eq 7 ] || echo "Verify did not exit with 7 on a tampered signature"
+cd ..
+
+echo " === Finding errors in blame. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+printf 'a\nb\nc\n' > f.txt && SCM_AUTHOR=ann "$SCM" init > /dev/null
+printf 'a\nB\nc\nd\n' > f.txt && SCM_AUTHOR=ann "$SCM" commit -a > /dev/null
+"$SCM" branch side > /dev/null && "$SCM" switch side > /dev/null
+printf 'x\na\nB\nc\nd\n' > f.txt && SCM_AUTHOR=bob "$SCM" commit -a > /dev/null
+"$SCM" switch main > /dev/null
+printf 'a\nB\nc\nd\ne\n' > f.txt && SCM_AUTHOR=ann "$SCM" commit -a > /dev/null
+"$SCM" merge side > /dev/null
+mv f.txt g.txt && "$SCM" commit -a > /dev/null
+[ "$("$SCM" blame g.txt | cut -d' ' -f1 | tr '\n' ' ')" = "#2 #0 #1 #0 #1 #3 " ] || echo "Blame attributed lines to the wrong commits"
+"$SCM" blame g.txt | head -1 | grep "(bob " > /dev/null || echo "Blame did not show the author of a line from a merged branch"
+[ "$("$SCM" blame f.txt "#0" | cut -d' ' -f1 | tr '\n' ' ')" = "#0 #0 #0 " ] || echo "Blame of an older commit was wrong"
+"$SCM" blame missing.txt > /dev/null 2>&1
+[ $? -eq 1 ] || echo "Blame of an untracked file did not exit with 1"
+cd ..
+
+echo " === Finding errors in stash. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+printf 'a\nb\nc\nd\n' > f.txt && echo keep > g.txt && "$SCM" init > /dev/null
+cp -r . "$SNAPS/clean" && rm -r "$SNAPS/clean"/.scm*
+printf 'a\nb\nc\nD\n' > f.txt && rm g.txt && echo new > h.txt
+cp -r . "$SNAPS/dirty" && rm -r "$SNAPS/dirty"/.scm*
+"$SCM" stash -m "first" > /dev/null || echo "Stash failed"
+diff -r --exclude='.scm*' . "$SNAPS/clean" > /dev/null || echo "Stash did not restore the checked-out commit"
+"$SCM" stash list | grep "stash@{0}: On main: first" > /dev/null || echo "Stash list did not show the entry"
+"$SCM" switch main > /dev/null || echo "Tree was not clean after stashing"
+"$SCM" stash pop > /dev/null || echo "Stash pop failed"
+diff -r --exclude='.scm*' . "$SNAPS/dirty" > /dev/null || echo "Stash pop did not bring back the changes"
+[ -z "$("$SCM" stash list)" ] || echo "Stash pop did not remove the entry"
+"$SCM" stash > /dev/null
+printf 'A\nb\nc\nd\n' > f.txt && "$SCM" commit -a > /dev/null
+"$SCM" stash pop > /dev/null || echo "Stash pop onto a newer commit failed"
+[ "$(cat f.txt)" = "$(printf 'A\nb\nc\nD')" ] || echo "Stash pop did not merge into a newer commit"
+"$SCM" commit -a > /dev/null
+printf 'A\nb\nc\nX\n' > f.txt && "$SCM" stash > /dev/null
+printf 'A\nb\nc\nY\n' > f.txt && "$SCM" commit -a > /dev/null
+"$SCM" stash pop > /dev/null 2>&1
+[ $? -eq 1 ] || echo "Conflicting stash pop did not exit with 1"
+grep "<<<<<<<" f.txt > /dev/null || echo "Conflicting stash pop did not leave conflict markers"
+[ "$("$SCM" stash list | wc -l)" -eq 1 ] || echo "Conflicting stash pop did not keep the entry"
+"$SCM" fsck > /dev/null || echo "Fsck found problems with a stash"
+"$SCM" stash drop > /dev/null || echo "Stash drop failed"
+"$SCM" stash drop > /dev/null 2>&1
+[ $? -eq 1 ] || echo "Dropping from an empty stash did not exit with 1"
+"$SCM" reset > /dev/null && printf 'A\nb\nc\nY\n' > f.txt
+echo staged >> g.txt && "$SCM" add g.txt && echo unstaged >> g.txt
+"$SCM" stash > /dev/null && "$SCM" stash pop > /dev/null || echo "Stash pop of staged changes failed"
+[ "$(tail -1 g.txt)" = "unstaged" ] || echo "Stash pop did not bring back unstaged changes"
+"$SCM" diff --cached | grep "^> staged$" > /dev/null || echo "Stash pop did not restage staged changes"
+"$SCM" diff --cached | grep "unstaged" > /dev/null && echo "Stash pop staged unstaged changes"
+cd ..
+
+echo " === Finding errors in log formatting. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+printf 'a\nb\n' > f.txt && echo 1 > g.txt && "$SCM" init -m "first" > /dev/null
+"$SCM" branch side > /dev/null && "$SCM" switch side > /dev/null
+printf 'a\nB\nc\n' > f.txt && "$SCM" commit -a -m "side work" > /dev/null
+"$SCM" switch main > /dev/null
+echo 2 >> g.txt && "$SCM" commit -a -m "main work" > /dev/null
+"$SCM" merge side -m "merge side" > /dev/null
+[ "$("$SCM" log --oneline | wc -l)" -eq 4 ] || echo "Log --oneline did not print one line per commit"
+"$SCM" log --oneline | head -1 | grep "^#3 [0-9a-f]\{16\} (main, checked out) merge side$" > /dev/null || echo "Log --oneline line was wrong"
+[ "$("$SCM" log --format '%i:%p:%s')" = "$(printf '3:#2 #1:merge side\n2:#0:main work\n1:#0:side work\n0::first')" ] || echo "Log --format was wrong"
+[ "$("$SCM" log --format '%%%n%x' | head -2 | tr '\n' ' ')" = "% %x " ] || echo "Log --format escapes were wrong"
+"$SCM" log --oneline --stat | grep "^ f.txt | +2 -1$" > /dev/null || echo "Log --stat miscounted lines"
+"$SCM" log --oneline --stat | grep "^ 2 files changed, 3 insertions(+), 0 deletions(-)$" > /dev/null || echo "Log --stat summary was wrong"
+[ "$("$SCM" log --format '%i' f.txt | tr '\n' ' ')" = "3 1 0 " ] || echo "Log <path> did not keep to commits touching the path"
+"$SCM" log --oneline --graph | grep "^|\\\\$" > /dev/null || echo "Log --graph did not branch at the merge"
+"$SCM" log --oneline --graph | grep "^|/$" > /dev/null || echo "Log --graph did not join the branches"
+"$SCM" log --graph | grep "^\* Commit #0" > /dev/null || echo "Log --graph did not mark commits"
+cd ..
+
+echo " === Finding errors in --json output. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+printf 'a\nb\n' > f.txt
+"$SCM" --json init -m "first" | grep '^{"version":1,"command":"init","ok":true,.*"index":0' > /dev/null || echo "Json init report was wrong"
+[ "$("$SCM" --json log | wc -l)" -eq 1 ] || echo "Json log was not a single line"
+"$SCM" --json log | grep '"merkle_root":' | grep '"message":"first"' > /dev/null || echo "Json log did not hold the commits"
+printf 'a\nB\n' > f.txt
+"$SCM" status --json > out.json
+[ $? -eq 6 ] || echo "Json status of a dirty tree did not exit 6"
+grep '"clean":false' out.json | grep '"path":"f.txt","staged":" ","unstaged":"M"' > /dev/null || echo "Json status entries were wrong"
+"$SCM" diff --json | grep '"hunks":\[{"added":\["B"\],"new":2,"old":2,"removed":\["b"\]}\]' > /dev/null || echo "Json diff hunks were wrong"
+printf 'a\nB' > f.txt
+"$SCM" diff --json | grep '"new_no_newline":true' | grep -v 'No newline' > /dev/null || echo "Json diff did not flag the missing final newline"
+printf 'a\nB\n' > f.txt
+HASH=$("$SCM" --json commit -a -m "second" | sed -n 's/.*"hash":"\([0-9a-f]*\)".*/\1/p')
+"$SCM" log --format '%H' | head -1 | grep "^$HASH$" > /dev/null || echo "Json commit did not report the new hash"
+"$SCM" --json checkout nope > out.json
+[ $? -eq 5 ] || echo "Json errors did not keep their exit code"
+grep '"ok":false' out.json | grep '"code":5' > /dev/null || echo "Json error report was wrong"
+"$SCM" --json > out.json
+[ $? -eq 2 ] || echo "Json without a command did not exit 2"
+grep '"ok":false' out.json > /dev/null || echo "Json without a command was reported as ok"
+{ (sleep 0.2; "$SCM" fsck) | true; } 2>&1 | grep panicked > /dev/null && echo "Fsck panicked when its reader went away"
+{ (sleep 0.2; "$SCM" --json fsck) | true; } 2>&1 | grep panicked > /dev/null && echo "Json report panicked when its reader went away"
+cd ..
+
+echo " === Finding errors in gc. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+seq 1 200 > f.txt && "$SCM" init -m "first" > /dev/null
+for i in 1 2 3 4 5 6 7 8 9 10; do
+    sed -i "${i}s/.*/changed $i/" f.txt && "$SCM" commit -a -m "change $i" > /dev/null
+done
+echo dropped >> f.txt && "$SCM" stash > /dev/null && "$SCM" stash drop > /dev/null
+echo kept >> f.txt && "$SCM" stash > /dev/null
+OUT=$("$SCM" gc --keyframes 4) || echo "Gc failed"
+echo "$OUT" | grep "^Removed 3 unreachable objects$" > /dev/null || echo "Gc did not remove the dropped stash's objects"
+echo "$OUT" | grep "bytes saved)$" > /dev/null || echo "Gc did not report bytes saved"
+[ -n "$(find .scm/deltas -type f)" ] || echo "Gc stored no deltas"
+"$SCM" --json gc --keyframes 4 | grep '"bytes_saved":0' > /dev/null || echo "A second gc changed the store"
+"$SCM" fsck > /dev/null 2>&1 || echo "Fsck found problems after gc"
+"$SCM" checkout "#3" > /dev/null && [ "$(sed -n 3p f.txt)" = "changed 3" ] && [ "$(sed -n 4p f.txt)" = "4" ] || echo "Checkout after gc restored the wrong content"
+"$SCM" switch main > /dev/null && "$SCM" stash pop > /dev/null && [ "$(tail -1 f.txt)" = "kept" ] || echo "Stash did not survive gc"
+cd .. && rm -rf "$WORK-clone" && "$SCM" clone "$WORK" "$WORK-clone" > /dev/null && cd "$WORK-clone"
+[ "$(seq 1 200 | sed 's/^\([1-9]\|10\)$/changed \1/')" = "$(cat f.txt)" ] || echo "Clone of a gc'd repository had the wrong content"
+[ -z "$(find .scm -name deltas)" ] || echo "Clone copied deltas instead of objects"
+cd .. && rm -rf "$WORK-clone"
+
+echo " === Finding errors migrating a single-file .scm. === "
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+H0=$(printf './f.txtone\n' | sha512sum | cut -d' ' -f1)
+H1=$(printf './f.txtone\ntwo\n' | sha512sum | cut -d' ' -f1)
+cat > .scm <<EOF
+{"latest": {"./f.txt": ["one", "two"]}, "merkle": [],
+ "commits": [{"hash": "$H0", "init": {"./f.txt": ["one"]}, "diff": {}},
+             {"hash": "$H1", "init": {}, "diff": {"./f.txt": ["1a2", "> two"]}}]}
+EOF
+printf 'one\ntwo\n' > f.txt
+"$SCM" status > /dev/null
+[ -d .scm ] && [ -f .scm.old ] || echo "Single-file .scm was not migrated"
+"$SCM" checkout "#0" > /dev/null
+[ "$(cat f.txt)" = "one" ] || echo "Mismatch after checking out migrated commit #0"
+"$SCM" checkout "$H1" > /dev/null
+[ "$(cat f.txt)" = "$(printf 'one\ntwo')" ] || echo "Mismatch after checking out migrated commit #1"
+rm -rf "$WORK" && mkdir "$WORK" && cd "$WORK"
+cat > .scm <<EOF
+{"commits": [{"hash": "$H0", "init": {"./f.txt": ["one"]}, "diff": {}},
+             {"hash": "$H1", "init": {}, "diff": {"./f.txt": ["5a6", "> two"]}}]}
+EOF
+"$SCM" log > /dev/null 2>&1
+[ $? -eq 7 ] || echo "Migrating a damaged .scm did not fail with corrupt history"
+[ -f .scm ] && [ ! -e .scm.old ] && [ ! -e .scm.migrating ] || echo "Failed migration did not leave the single file in place"
+"$SCM" log > /dev/null 2>&1
+[ $? -eq 7 ] || echo "Failed migration was taken as a repository the second time"
+
+cd - > /dev/null
+rm -rf "$WORK" "$SNAPS"
+echo " === Errors printed. No errors denotes \"Perfect!\" === "